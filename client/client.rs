@@ -9,7 +9,7 @@ use chrono::prelude::*;
 use serde_json as json;
 use tokio::codec::{Decoder, Encoder};
 
-use event::Reason;
+use event::{Event, Reason};
 use master_types::{MasterRequest, MasterResponse};
 use version::PKG_INFO;
 
@@ -19,12 +19,21 @@ pub enum ClientCommand {
     Start(String),
     Pause(String),
     Resume(String),
-    Reload(String),
+    Reload(String, bool),
     Restart(String),
+    Scale(String, u16, bool),
+    Deploy(String, String, bool),
+    Rollback(String),
     Stop(String),
     Status(String),
     SPid(String),
+    Output(String, Option<usize>, Option<usize>, bool),
+    History(String, Option<u64>, Option<String>),
+    Events(Vec<String>, Vec<String>),
     Pid,
+    Upgrade,
+    ReopenLogs,
+    LogLevel(String, Option<String>, Option<u64>),
     Quit,
     Version,
     VersionCheck,
@@ -129,6 +138,26 @@ pub fn run(cmd: ClientCommand, sock: &str) -> bool {
         ClientCommand::SPid(name) => {
             send_command(&mut stream, MasterRequest::SPid(name))
         }
+        ClientCommand::Output(service, worker, lines, follow) => send_command(
+            &mut stream,
+            MasterRequest::Logs {
+                service,
+                worker,
+                lines,
+                follow,
+            },
+        ),
+        ClientCommand::History(service, since, reason) => send_command(
+            &mut stream,
+            MasterRequest::History {
+                service,
+                since,
+                reason,
+            },
+        ),
+        ClientCommand::Events(services, states) => {
+            send_command(&mut stream, MasterRequest::Subscribe { services, states })
+        }
         ClientCommand::Pause(name) => {
             println!("Pause `{}` service.", name);
             send_command(&mut stream, MasterRequest::Pause(name))
@@ -141,14 +170,30 @@ pub fn run(cmd: ClientCommand, sock: &str) -> bool {
             print!("Starting `{}` service.", name);
             send_command(&mut stream, MasterRequest::Start(name))
         }
-        ClientCommand::Reload(name) => {
+        ClientCommand::Reload(name, canary) => {
             print!("Reloading `{}` service.", name);
-            send_command(&mut stream, MasterRequest::Reload(name))
+            if canary {
+                send_command(&mut stream, MasterRequest::CanaryReload(name))
+            } else {
+                send_command(&mut stream, MasterRequest::Reload(name))
+            }
         }
         ClientCommand::Restart(name) => {
             print!("Restarting `{}` service", name);
             send_command(&mut stream, MasterRequest::Restart(name))
         }
+        ClientCommand::Deploy(name, release, canary) => {
+            print!("Deploying release `{}` of `{}` service.", release, name);
+            send_command(&mut stream, MasterRequest::Deploy(name, release, canary))
+        }
+        ClientCommand::Rollback(name) => {
+            print!("Rolling back `{}` service.", name);
+            send_command(&mut stream, MasterRequest::Rollback(name))
+        }
+        ClientCommand::Scale(name, num, persist) => {
+            print!("Scaling `{}` service to {} workers.", name, num);
+            send_command(&mut stream, MasterRequest::Scale(name, num, persist))
+        }
         ClientCommand::Stop(name) => {
             print!("Stopping `{}` service.", name);
             send_command(&mut stream, MasterRequest::Stop(name))
@@ -157,6 +202,21 @@ pub fn run(cmd: ClientCommand, sock: &str) -> bool {
         ClientCommand::Version | ClientCommand::VersionCheck => {
             send_command(&mut stream, MasterRequest::Version)
         }
+        ClientCommand::Upgrade => {
+            print!("Upgrading master process.");
+            send_command(&mut stream, MasterRequest::Upgrade)
+        }
+        ClientCommand::ReopenLogs => {
+            print!("Reopening log files.");
+            send_command(&mut stream, MasterRequest::ReopenLogs)
+        }
+        ClientCommand::LogLevel(level, module, timeout) => {
+            print!("Setting log level to {}.", level);
+            send_command(
+                &mut stream,
+                MasterRequest::SetLogLevel(level, module, timeout),
+            )
+        }
         ClientCommand::Quit => {
             print!("Quiting.");
             send_command(&mut stream, MasterRequest::Quit)
@@ -173,11 +233,20 @@ pub fn run(cmd: ClientCommand, sock: &str) -> bool {
     loop {
         match try_read_response(&mut stream, &mut buf) {
             Ok(MasterResponse::Pong) => {
+                match cmd {
+                    ClientCommand::Output(..)
+                    | ClientCommand::History(..)
+                    | ClientCommand::Events(..) => continue,
+                    _ => (),
+                }
                 print!(".");
                 let _ = io::stdout().flush();
             }
             Ok(MasterResponse::Done) => {
-                println!();
+                match cmd {
+                    ClientCommand::Output(..) | ClientCommand::History(..) => (),
+                    _ => println!(),
+                }
                 return true;
             }
             Ok(MasterResponse::Pid(pid)) => {
@@ -196,34 +265,54 @@ pub fn run(cmd: ClientCommand, sock: &str) -> bool {
                 return true;
             }
             Ok(MasterResponse::ServiceStatus(status)) => {
-                println!("Service status: {}", status.0);
-                for worker in status.1 {
+                println!("Service status: {}", status.status);
+                println!("Workers: {}", status.workers);
+                if let Some(release) = status.release {
+                    println!("Release: {}", release);
+                }
+                if !status.orphans.is_empty() {
+                    println!("Orphaned processes:");
+                    for orphan in status.orphans {
+                        println!("  {}", orphan);
+                    }
+                }
+                for worker in status.events {
                     for ev in worker.1 {
-                        let dt = Local.timestamp(ev.timestamp as i64, 0);
-                        print!("{} {}: ", worker.0, dt.format("%Y-%m-%d %H:%M:%S"));
-                        if let Some(ref pid) = ev.pid {
-                            print!("(pid:{}) ", pid)
-                        }
-                        print!("{:?}", ev.state);
-                        match ev.reason {
-                            Reason::None | Reason::Initial => (),
-                            _ => print!(", reason: {:?}", ev.reason),
-                        }
-                        println!();
+                        print_event(&worker.0, &ev);
                     }
                 }
                 return true;
             }
+            Ok(MasterResponse::HistoryEvent(rec)) => {
+                print_event(&format!("worker({})", rec.worker), &rec.event);
+            }
+            Ok(MasterResponse::Event(rec)) => {
+                print_event(
+                    &format!("{} worker({})", rec.service, rec.worker),
+                    &rec.event,
+                );
+                let _ = io::stdout().flush();
+            }
             Ok(MasterResponse::ServiceWorkerPids(pids)) => {
                 for pid in pids {
                     println!("{}", pid);
                 }
                 return true;
             }
+            Ok(MasterResponse::ServiceLog(line)) => {
+                println!(
+                    "worker({}) (pid:{}) {}: {}",
+                    line.worker, line.pid, line.stream, line.line
+                );
+            }
             Ok(MasterResponse::ServiceFailed) => {
                 println!("failed.");
                 return false;
             }
+            Ok(MasterResponse::ServiceCanaryFailed) => {
+                println!("canary failed.");
+                return false;
+            }
             Ok(MasterResponse::ErrorNotReady) => {
                 error!("Service is loading");
                 return false;
@@ -236,6 +325,27 @@ pub fn run(cmd: ClientCommand, sock: &str) -> bool {
                 error!("Service is starting");
                 return false;
             }
+            Ok(MasterResponse::ErrorUnknownRelease) => {
+                error!("Release is unknown");
+                return false;
+            }
+            Ok(MasterResponse::ErrorScaleOutOfRange(min, max)) => {
+                error!("Number of workers has to be between {} and {}", min, max);
+                return false;
+            }
+            Ok(MasterResponse::ErrorUnknownLogLevel) => {
+                println!();
+                error!("Log level is unknown");
+                return false;
+            }
+            Ok(MasterResponse::ErrorUpgrade(err)) => {
+                error!("Upgrade failed: {}", err);
+                return false;
+            }
+            Ok(MasterResponse::ErrorHistory(err)) => {
+                error!("Can not read history: {}", err);
+                return false;
+            }
             Ok(MasterResponse::ErrorServiceReloading) => {
                 error!("Service is restarting");
                 return false;
@@ -254,6 +364,20 @@ pub fn run(cmd: ClientCommand, sock: &str) -> bool {
     }
 }
 
+fn print_event(worker: &str, ev: &Event) {
+    let dt = Local.timestamp(ev.timestamp as i64, 0);
+    print!("{} {}: ", worker, dt.format("%Y-%m-%d %H:%M:%S"));
+    if let Some(ref pid) = ev.pid {
+        print!("(pid:{}) ", pid)
+    }
+    print!("{:?}", ev.state);
+    match ev.reason {
+        Reason::None | Reason::Initial => (),
+        _ => print!(", reason: {:?}", ev.reason),
+    }
+    println!();
+}
+
 pub struct ClientTransportCodec;
 
 impl Encoder for ClientTransportCodec {
@@ -1,3 +1,5 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
 use client::ClientCommand;
 use structopt::StructOpt;
 
@@ -7,11 +9,48 @@ struct Cli {
     #[structopt(long = "sock", short = "m", default_value = "fectld.sock")]
     sock: String,
 
-    /// Run command (Supported commands: status, start, reload, restart, stop)
+    /// Run command (Supported commands: status, start, reload, restart, stop, scale,
+    /// deploy, rollback, output, history, events, upgrade, reopen-logs, log-level)
     command: String,
 
     /// Service name
     name: Option<String>,
+
+    /// Command argument, number of workers for `scale`, release for `deploy`
+    /// or worker number for `output`
+    value: Option<String>,
+
+    /// Number of last buffered lines for `output`, all buffered lines by default
+    #[structopt(long = "lines", short = "n")]
+    lines: Option<usize>,
+
+    /// Follow service output for `output`
+    #[structopt(long = "follow", short = "f")]
+    follow: bool,
+
+    /// Show events recorded within period for `history`, i.e. `30m`, `2h` or `7d`
+    #[structopt(long = "since")]
+    since: Option<String>,
+
+    /// Show events with reason for `history`, i.e. `HeartbeatFailed`
+    #[structopt(long = "reason")]
+    reason: Option<String>,
+
+    /// Comma separated list of worker states for `events`, i.e. `Running,Failed`
+    #[structopt(long = "state")]
+    state: Option<String>,
+
+    /// Restore configured log level after timeout in seconds for `log-level`
+    #[structopt(long = "revert")]
+    revert: Option<u64>,
+
+    /// Persist number of workers across master restarts
+    #[structopt(long = "persist")]
+    persist: bool,
+
+    /// Reload one worker first, reload remaining workers if it stays healthy
+    #[structopt(long = "canary")]
+    canary: bool,
 }
 
 pub fn load_config() -> Option<(ClientCommand, String)> {
@@ -24,8 +63,28 @@ pub fn load_config() -> Option<(ClientCommand, String)> {
     match cmd.as_str() {
         "pid" => return Some((ClientCommand::Pid, sock)),
         "quit" => return Some((ClientCommand::Quit, sock)),
+        "upgrade" => return Some((ClientCommand::Upgrade, sock)),
+        "reopen-logs" => return Some((ClientCommand::ReopenLogs, sock)),
         "version" => return Some((ClientCommand::Version, sock)),
         "version-check" => return Some((ClientCommand::VersionCheck, sock)),
+        // events are streamed until client is interrupted, `--follow` is implied
+        "events" => {
+            let services = args.name.as_ref().map(|s| split(s)).unwrap_or_default();
+            let states = args.state.as_ref().map(|s| split(s)).unwrap_or_default();
+            return Some((ClientCommand::Events(services, states), sock));
+        }
+        "log-level" => {
+            return match args.name {
+                Some(level) => Some((
+                    ClientCommand::LogLevel(level, args.value.clone(), args.revert),
+                    sock,
+                )),
+                None => {
+                    println!("Log level is required");
+                    None
+                }
+            }
+        }
         _ => (),
     }
 
@@ -40,12 +99,51 @@ pub fn load_config() -> Option<(ClientCommand, String)> {
     let cmd = match cmd.as_str() {
         "status" => ClientCommand::Status(name),
         "spid" => ClientCommand::SPid(name),
+        // `logs` is alias of `output`
+        "output" | "logs" => match args.value.as_ref().map(|v| v.parse::<usize>()) {
+            None => ClientCommand::Output(name, None, args.lines, args.follow),
+            Some(Ok(worker)) => {
+                ClientCommand::Output(name, Some(worker), args.lines, args.follow)
+            }
+            Some(Err(_)) => {
+                println!("Worker number is required");
+                return None;
+            }
+        },
         "start" => ClientCommand::Start(name),
         "stop" => ClientCommand::Stop(name),
-        "reload" => ClientCommand::Reload(name),
+        "reload" => ClientCommand::Reload(name, args.canary),
         "restart" => ClientCommand::Restart(name),
         "pause" => ClientCommand::Pause(name),
         "resume" => ClientCommand::Resume(name),
+        "deploy" => match args.value {
+            Some(ref release) => {
+                ClientCommand::Deploy(name, release.clone(), args.canary)
+            }
+            None => {
+                println!("Release name is required");
+                return None;
+            }
+        },
+        "rollback" => ClientCommand::Rollback(name),
+        "history" => {
+            let since = match args.since.as_ref().map(|s| parse_period(s)) {
+                None => None,
+                Some(Some(secs)) => Some(now().saturating_sub(secs)),
+                Some(None) => {
+                    println!("Can not parse period, i.e. `30m`, `2h` or `7d`");
+                    return None;
+                }
+            };
+            ClientCommand::History(name, since, args.reason.clone())
+        }
+        "scale" => match args.value.as_ref().map(|v| v.parse::<u16>()) {
+            Some(Ok(num)) => ClientCommand::Scale(name, num, args.persist),
+            _ => {
+                println!("Number of workers is required");
+                return None;
+            }
+        },
         _ => {
             println!("Unknown command: {}", cmd);
             return None;
@@ -53,3 +151,59 @@ pub fn load_config() -> Option<(ClientCommand, String)> {
     };
     return Some((cmd, sock));
 }
+
+// period in seconds, number with `s`, `m`, `h` or `d` suffix
+fn parse_period(period: &str) -> Option<u64> {
+    let period = period.trim();
+    let (num, mult) = match period.chars().last()? {
+        's' => (&period[..period.len() - 1], 1),
+        'm' => (&period[..period.len() - 1], 60),
+        'h' => (&period[..period.len() - 1], 3600),
+        'd' => (&period[..period.len() - 1], 86400),
+        _ => (period, 1),
+    };
+    num.parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(mult))
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+// comma separated list
+fn split(s: &str) -> Vec<String> {
+    s.split(',')
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_owned())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_period() {
+        assert_eq!(parse_period("30"), Some(30));
+        assert_eq!(parse_period("30s"), Some(30));
+        assert_eq!(parse_period("5m"), Some(300));
+        assert_eq!(parse_period(" 2h "), Some(7200));
+        assert_eq!(parse_period("1d"), Some(86400));
+        assert_eq!(parse_period(""), None);
+        assert_eq!(parse_period("m"), None);
+        assert_eq!(parse_period("-1m"), None);
+        assert_eq!(parse_period("1w"), None);
+        assert_eq!(parse_period("18446744073709551615d"), None);
+    }
+
+    #[test]
+    fn test_split() {
+        assert_eq!(split("a, b,,c "), vec!["a", "b", "c"]);
+        assert!(split("").is_empty());
+    }
+}
@@ -22,12 +22,18 @@ def close_on_exec(fd):
     fcntl.fcntl(fd, fcntl.F_SETFD, flags)
 
 
+# frame size is packed as signed 16 bit integer
+MAX_MESSAGE_SIZE = 32767
+
+
 def pack_message(cmd, data=None):
     msg = {'cmd': str(cmd)}
     if data is not None:
         msg['data'] = data
 
     msg = json.dumps(msg).encode('utf-8')
+    if len(msg) > MAX_MESSAGE_SIZE:
+        raise ValueError('Message is too large: %d bytes' % len(msg))
     return struct.pack('>h', len(msg)) + msg
 
 
@@ -37,9 +43,11 @@ CMD_PAUSE = 'pause'
 CMD_RESUME = 'resume'
 CMD_STOP = 'stop'
 CMD_HEARTBEAT = 'hb'
+CMD_REOPEN = 'reopen'
 
 ALL_COMMANDS = (CMD_PREPARE, CMD_START,
-                CMD_PAUSE, CMD_RESUME, CMD_STOP, CMD_HEARTBEAT)
+                CMD_PAUSE, CMD_RESUME, CMD_STOP, CMD_HEARTBEAT,
+                CMD_REOPEN)
 
 
 def unpack_message(data):
@@ -281,6 +281,9 @@ class AsyncioWorker(Worker):
                     for app in self._apps:
                         yield from app.start()
 
+                elif cmd == self.CMD_REOPEN:
+                    self.reopen_logs()
+
                 elif cmd == self.CMD_STOP:
                     # init closing process
                     self._stopping = asyncio.ensure_future(
@@ -1,6 +1,8 @@
 from __future__ import absolute_import, print_function
 
 import itertools
+import json
+import logging
 import os
 import random
 import signal
@@ -13,6 +15,10 @@ from .socket import Socket
 
 _sentinel = object()
 
+# log record has to fit into pipe protocol frame
+MAX_LOG_MESSAGE = 4096
+MAX_LOG_FIELD = 1024
+
 
 class Worker(object):
 
@@ -23,6 +29,8 @@ class Worker(object):
     MSG_RESTART = 'restart'
     MSG_HEARTBEAT = 'hb'
     MSG_CFG_ERROR = 'cfgerror'
+    MSG_LOAD = 'load'
+    MSG_LOG = 'log'
 
     CMD_PREPARE = 'prepare'
     CMD_START = 'start'
@@ -30,9 +38,11 @@ class Worker(object):
     CMD_RESUME = 'resume'
     CMD_STOP = 'stop'
     CMD_HEARTBEAT = 'hb'
+    CMD_REOPEN = 'reopen'
 
     ALL_COMMANDS = (CMD_PREPARE, CMD_START,
-                    CMD_PAUSE, CMD_RESUME, CMD_STOP, CMD_HEARTBEAT)
+                    CMD_PAUSE, CMD_RESUME, CMD_STOP, CMD_HEARTBEAT,
+                    CMD_REOPEN)
 
     SIGNALS = [getattr(signal, "SIG%s" % x)
                for x in "ABRT HUP QUIT INT TERM USR1 WINCH CHLD".split()]
@@ -86,6 +96,61 @@ class Worker(object):
     def heartbeat(self):
         self.notify(self.MSG_HEARTBEAT)
 
+    def report_load(self, load):
+        """ report worker load (busy fraction, queue depth, etc) """
+        self.notify(self.MSG_LOAD, float(load))
+
+    def log(self, level, msg, **fields):
+        """ send log record to master process """
+        msg = str(msg)[:MAX_LOG_MESSAGE]
+        record = {'level': str(level), 'msg': msg, 'fields': {}}
+
+        # escaped non-ascii text may still exceed frame size
+        size = self._log_size(record)
+        while size > utils.MAX_MESSAGE_SIZE:
+            msg = msg[:len(msg) // 2]
+            record['msg'] = msg
+            size = self._log_size(record)
+
+        # fields that do not fit into frame are dropped
+        for k, v in fields.items():
+            k, v = str(k)[:MAX_LOG_FIELD], str(v)[:MAX_LOG_FIELD]
+            item = len(json.dumps({k: v}))
+            if size + item <= utils.MAX_MESSAGE_SIZE:
+                record['fields'][k] = v
+                size += item
+
+        self.notify(self.MSG_LOG, record)
+
+    def _log_size(self, record):
+        return len(json.dumps({'cmd': self.MSG_LOG, 'data': record}))
+
+    def log_handler(self, level=logging.NOTSET):
+        """ logging handler that forwards records to master process """
+        return MasterLogHandler(self, level)
+
+    def reopen_logs(self):
+        """ reopen stdout/stderr redirect files and logging file handlers """
+        for name, fd in (('FECTL_STDOUT', 1), ('FECTL_STDERR', 2)):
+            path = os.environ.get(name)
+            if path is None:
+                continue
+            try:
+                new_fd = os.open(path, os.O_WRONLY | os.O_APPEND | os.O_CREAT)
+                os.dup2(new_fd, fd)
+                os.close(new_fd)
+            except OSError as exc:
+                logging.error("Can not reopen %s: %s", path, exc)
+
+        for handler in logging.root.handlers:
+            if isinstance(handler, logging.FileHandler):
+                handler.acquire()
+                try:
+                    handler.close()
+                    handler.stream = handler._open()
+                finally:
+                    handler.release()
+
     def on_shutdown(self, cb):
         """ register callback for graceful shutdown process """
         self._on_shutdown.append(cb)
@@ -138,7 +203,7 @@ class Worker(object):
             signal.set_wakeup_fd(self._pipe[1])
 
     def _handle_usr1(self, sig, frame):
-        pass
+        self.reopen_logs()
 
     def _handle_exit(self, sig, frame):
         self._alive = False
@@ -155,3 +220,25 @@ class Worker(object):
     def _handle_winch(self, sig, fname):
         # Ignore SIGWINCH in worker. Fixes a crash on OpenBSD.
         pass
+
+
+class MasterLogHandler(logging.Handler):
+    """Forward log records to master process, i.e.
+
+       logging.root.addHandler(worker.log_handler())
+
+    Records are written to master log with service name and worker pid.
+    """
+
+    def __init__(self, worker, level=logging.NOTSET):
+        super(MasterLogHandler, self).__init__(level)
+        self._worker = worker
+
+    def emit(self, record):
+        try:
+            msg = self.format(record)
+            self._worker.log(
+                record.levelname, msg, logger=record.name,
+                module=record.module, line=record.lineno)
+        except Exception:
+            self.handleError(record)
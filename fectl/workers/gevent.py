@@ -76,6 +76,9 @@ class GeventWorker(Worker):
                 self._alive = False
                 break
 
+            if cmd == self.CMD_REOPEN:
+                self.reopen_logs()
+
     def _run(self):
         gevent.spawn(self._read_loop)
         gevent.spawn(self._write_loop)
@@ -1,18 +1,35 @@
 use std::collections::HashMap;
+use std::io;
+use std::os::unix::io::RawFd;
 use std::rc::Rc;
+use std::time::Duration;
 
+use libc;
+use nix::sys::signal::{kill, Signal};
 use nix::sys::wait::{waitpid, WaitStatus, WaitPidFlag};
-use nix::unistd::getpid;
+use nix::unistd::{getpid, Pid};
+use tokio_signal;
 
 use actix::actors::signal;
 use actix::prelude::*;
 use actix::Response;
-use futures::Future;
+use futures::{future, Future};
 
-use config::Config;
+use config::{Config, ServiceConfig};
 use event::{Reason, ServiceStatus};
+use history::{self, History};
+use master;
+use master_types::LogLine;
+use notify;
+use output::FollowLine;
+use persist::{MasterState, ServiceJournal};
 use process::ProcessError;
+use reaper;
 use service::{self, FeService, ReloadStatus, ServiceOperationError, StartStatus};
+use upgrade::{self, UpgradeState};
+
+/// Delay of saving journal changes, changes within delay are saved at once
+const JOURNAL_DELAY: Duration = Duration::from_millis(500);
 
 #[derive(Debug)]
 /// Command center errors
@@ -25,35 +42,337 @@ pub enum CommandError {
     ServiceStopped,
     /// underlying service error
     Service(ServiceOperationError),
+    /// master binary upgrade failed
+    Upgrade(String),
 }
 
 #[derive(PartialEq, Debug)]
 enum State {
     Starting,
     Running,
+    Upgrading,
     Stopping,
 }
 
 pub struct CommandCenter {
     cfg: Rc<Config>,
     state: State,
+    sock: RawFd,
+    lock: RawFd,
+    upgrade: Option<UpgradeState>,
+    persisted: MasterState,
     services: HashMap<String, Addr<FeService>>,
     stop_waiter: Option<actix::utils::Condition<bool>>,
     stopping: usize,
+    /// journal changes are not saved yet
+    journaled: bool,
+    /// services waiting for termination of surviving workers
+    terminating: usize,
+    /// service manager is notified about readiness
+    ready: bool,
+    /// last status reported to service manager
+    status: String,
 }
 
 impl CommandCenter {
-    pub fn start(cfg: Rc<Config>) -> Addr<CommandCenter> {
+    pub fn start(
+        cfg: Rc<Config>, sock: RawFd, lock: RawFd, upgrade: Option<UpgradeState>,
+    ) -> Addr<CommandCenter> {
         CommandCenter {
+            persisted: MasterState::load(&cfg.master.state),
             cfg,
+            sock,
+            lock,
+            upgrade,
             state: State::Starting,
             services: HashMap::new(),
             stop_waiter: None,
             stopping: 0,
+            journaled: false,
+            terminating: 0,
+            ready: false,
+            status: String::new(),
         }.start()
     }
 
+    fn save_state(&mut self) {
+        self.journaled = false;
+        if let Err(err) = self.persisted.save(&self.cfg.master.state) {
+            error!(
+                "Can not write state file {:?} err: {}",
+                self.cfg.master.state, err
+            );
+        }
+    }
+
+    // remember active release, `None` is rollback to previous release
+    fn release_deployed(&mut self, name: &str, release: Option<String>) {
+        let (current, previous) = self.persisted.release(name);
+        let release = match release {
+            Some(release) => Some(release),
+            None => previous,
+        };
+        self.persisted.set_release(name, release, current);
+        self.save_state();
+    }
+
+    fn start_service(
+        &mut self, cfg: &ServiceConfig, crashed: bool, ctx: &mut Context<Self>,
+    ) {
+        let journal = self.persisted.journal(&cfg.name);
+
+        // runtime number of workers survives crash
+        let num = match self.persisted.num(&cfg.name) {
+            Some(num) => num,
+            None if crashed && journal.num > 0 => journal.num as u16,
+            None => cfg.num,
+        };
+
+        // releases could be removed from config
+        let (mut release, mut previous) = self.persisted.release(&cfg.name);
+        if let Some(ref name) = release {
+            if !cfg.release.contains_key(name) {
+                warn!("Release {:?} of {:?} is not configured", name, cfg.name);
+            }
+        }
+        release = release.filter(|name| cfg.release.contains_key(name));
+        previous = previous.filter(|name| cfg.release.contains_key(name));
+
+        let service = FeService::start(
+            num,
+            release,
+            previous,
+            journal,
+            cfg.clone(),
+            ctx.address().recipient(),
+        );
+        self.services.insert(cfg.name.clone(), service);
+    }
+
+    // service starts once surviving workers of crashed master are gone
+    fn terminate(
+        &mut self, cfg: ServiceConfig, mut survivors: reaper::Survivors, crashed: bool,
+        ctx: &mut Context<Self>,
+    ) {
+        if !survivors.poll() {
+            ctx.run_later(Duration::from_millis(100), move |act, ctx| {
+                act.terminate(cfg, survivors, crashed, ctx)
+            });
+            return;
+        }
+
+        self.terminating -= 1;
+        match self.state {
+            State::Starting | State::Running => self.start_service(&cfg, crashed, ctx),
+            _ => info!("Service {:?} is not started", cfg.name),
+        }
+    }
+
+    // suspend or resume worker restarts in all services
+    fn freeze(&self, frozen: bool) {
+        for srv in self.services.values() {
+            srv.do_send(service::Freeze(frozen));
+        }
+    }
+
+    // collect state of all services
+    fn handover(
+        &mut self,
+    ) -> Box<ActorFuture<Item = UpgradeState, Error = CommandError, Actor = Self>> {
+        let services: Vec<_> = self
+            .services
+            .iter()
+            .map(|(name, srv)| {
+                let name = name.clone();
+                srv.send(service::Handover).then(move |res| match res {
+                    Ok(Ok(service)) => Ok((name, service)),
+                    Ok(Err(err)) => Err(CommandError::Service(err)),
+                    Err(_) => Err(CommandError::NotReady),
+                })
+            }).collect();
+
+        Box::new(
+            future::join_all(services)
+                .into_actor(self)
+                .map(|services, act, _| UpgradeState {
+                    sock: act.sock,
+                    lock: Some(act.lock),
+                    services: services.into_iter().collect(),
+                }),
+        )
+    }
+
+    // collect state of all services and execute new master binary,
+    // workers are not restarted until new master process adopts them
+    fn upgrade(
+        &mut self,
+    ) -> Box<ActorFuture<Item = (), Error = CommandError, Actor = Self>> {
+        info!("Upgrading master process");
+        self.freeze(true);
+
+        Box::new(
+            self.handover()
+                .and_then(|state, act, ctx| actix::fut::result(act.exec(&state, ctx)))
+                .map_err(|err, act, _| {
+                    act.freeze(false);
+                    err
+                }),
+        )
+    }
+
+    fn exec(
+        &mut self, state: &UpgradeState, ctx: &mut Context<Self>,
+    ) -> Result<(), CommandError> {
+        if self.state != State::Running || self.terminating > 0 {
+            return Err(CommandError::NotReady);
+        }
+
+        let path = upgrade::state_path(&self.cfg.master.state);
+        if let Err(err) = state.save(&path) {
+            error!("Can not write upgrade state {:?} err: {}", path, err);
+            return Err(CommandError::Upgrade(format!("{}", err)));
+        }
+
+        // client has to receive response before process image gets replaced
+        self.state = State::Upgrading;
+        notify::notify("RELOADING=1");
+        ctx.run_later(Duration::from_millis(100), move |act, ctx| {
+            // workers could exit since state was collected
+            act.handover()
+                .then(move |res, act, _| {
+                    if act.journaled {
+                        act.save_state();
+                    }
+                    let err = match res.map(|state| (state.save(&path), state)) {
+                        Ok((Ok(_), state)) => format!("{}", state.exec(&path)),
+                        Ok((Err(err), _)) => format!("{}", err),
+                        Err(err) => format!("{:?}", err),
+                    };
+                    error!("Can not execute master binary: {}", err);
+                    let _ = std::fs::remove_file(&path);
+                    act.state = State::Running;
+                    act.freeze(false);
+                    notify::ready();
+                    actix::fut::ok(())
+                }).spawn(ctx);
+        });
+        Ok(())
+    }
+
+    // restore services handed over by previous master process
+    fn adopt(&mut self, mut state: UpgradeState, ctx: &mut Context<Self>) {
+        info!("Adopting services of previous master process");
+        let recipient = ctx.address().recipient();
+
+        for cfg in &self.cfg.services {
+            if let Some(service) = state.services.remove(&cfg.name) {
+                let journal = self.persisted.journal(&cfg.name);
+                let service =
+                    FeService::adopt(service, journal, cfg.clone(), recipient.clone());
+                self.services.insert(cfg.name.clone(), service);
+            }
+        }
+
+        // services removed from config
+        for (name, service) in state.services {
+            warn!("Service {:?} is not configured, stopping workers", name);
+            for worker in service.workers {
+                if let Some(process) = worker.process {
+                    let _ = kill(Pid::from_raw(process.pid), Signal::SIGTERM);
+                }
+            }
+        }
+    }
+
+    // reap exited worker processes
+    fn reap(&mut self) {
+        debug!("Reap workers");
+        loop {
+            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, code)) => {
+                    info!("Worker {} exit code: {}", pid, code);
+                    reaper::exited(pid);
+                    let err = ProcessError::from(code as i8);
+                    for srv in self.services.values_mut() {
+                        srv.do_send(service::ProcessExited(pid, err.clone()));
+                    }
+                    continue;
+                }
+                Ok(WaitStatus::Signaled(pid, sig, _)) => {
+                    info!("Worker {} exit by signal {:?}", pid, sig);
+                    reaper::exited(pid);
+                    let err = ProcessError::Signal(sig as usize);
+                    for srv in self.services.values_mut() {
+                        srv.do_send(service::ProcessExited(pid, err.clone()));
+                    }
+                    continue;
+                }
+                Ok(_) => (),
+                Err(_) => (),
+            }
+            break;
+        }
+    }
+
+    // report state of services to service manager, master is ready
+    // when none of services is starting
+    fn notify_status(&mut self, ctx: &mut Context<Self>) {
+        if self.state != State::Running {
+            return;
+        }
+
+        let mut names: Vec<_> = self.services.keys().cloned().collect();
+        names.sort();
+        let statuses: Vec<_> = names
+            .iter()
+            .map(|name| self.services[name].send(service::Status(false)))
+            .collect();
+
+        future::join_all(statuses)
+            .into_actor(self)
+            .map(move |statuses, act, _| {
+                let status = names
+                    .iter()
+                    .zip(statuses.iter())
+                    .map(|(name, status)| match *status {
+                        Ok(ref status) => format!("{}: {}", name, status.status),
+                        Err(_) => format!("{}: unknown", name),
+                    }).collect::<Vec<_>>()
+                    .join(", ");
+                if status != act.status {
+                    notify::notify(&format!("STATUS={}", status));
+                    act.status = status;
+                }
+
+                let starting = statuses.iter().any(|status| match *status {
+                    Ok(ref status) => status.status == "starting",
+                    Err(_) => false,
+                });
+                if !act.ready && !starting && act.state == State::Running {
+                    info!("Master process is ready");
+                    act.ready = true;
+                    notify::ready();
+                }
+            }).map_err(|_, _, _| ())
+            .spawn(ctx);
+    }
+
+    fn reopen_logs(&mut self) {
+        info!("Reopening log files");
+        if self.cfg.master.daemon {
+            master::redirect_output(&self.cfg.master);
+        }
+        for service in self.services.values() {
+            service.do_send(service::ReopenLogs);
+        }
+    }
+
     fn exit(&mut self) {
+        if self.persisted.running {
+            self.persisted.running = false;
+            self.save_state();
+        }
+
         if let Some(waiter) = self.stop_waiter.take() {
             waiter.set(true);
         }
@@ -66,6 +385,7 @@ impl CommandCenter {
             info!("Stopping service");
 
             self.state = State::Stopping;
+            notify::notify("STOPPING=1");
             for service in self.services.values() {
                 self.stopping += 1;
                 service
@@ -83,6 +403,11 @@ impl CommandCenter {
                         }
                     }).spawn(ctx);
             }
+
+            // services could still wait for surviving workers
+            if self.stopping == 0 {
+                self.exit();
+            }
         }
     }
 }
@@ -113,6 +438,38 @@ impl Handler<ServicePids> for CommandCenter {
     }
 }
 
+/// Captured output of the service workers
+pub struct ServiceLogs {
+    pub name: String,
+    pub worker: Option<usize>,
+    pub lines: Option<usize>,
+    pub follow: Option<Recipient<FollowLine>>,
+}
+
+impl Message for ServiceLogs {
+    type Result = Result<Vec<LogLine>, CommandError>;
+}
+
+impl Handler<ServiceLogs> for CommandCenter {
+    type Result = Response<Vec<LogLine>, CommandError>;
+
+    fn handle(
+        &mut self, msg: ServiceLogs, _: &mut Context<CommandCenter>,
+    ) -> Self::Result {
+        match self.state {
+            State::Running => match self.services.get(&msg.name) {
+                Some(service) => Response::async(
+                    service
+                        .send(service::Logs(msg.worker, msg.lines, msg.follow))
+                        .map_err(|_| CommandError::UnknownService),
+                ),
+                None => Response::reply(Err(CommandError::UnknownService)),
+            },
+            _ => Response::reply(Err(CommandError::NotReady)),
+        }
+    }
+}
+
 #[derive(Message)]
 #[rtype(result = "Result<bool, ()>")]
 pub struct Stop;
@@ -152,13 +509,15 @@ impl Handler<StartService> for CommandCenter {
             State::Running => {
                 info!("Starting service {:?}", msg.0);
                 match self.services.get(&msg.0) {
-                    Some(service) => Response::async(service.send(service::Start).then(
-                        |res| match res {
-                            Ok(Ok(status)) => Ok(status),
-                            Ok(Err(err)) => Err(CommandError::Service(err)),
-                            Err(_) => Err(CommandError::NotReady),
-                        },
-                    )),
+                    Some(service) => Response::async(
+                        service
+                            .send(service::Start(Reason::ConsoleRequest))
+                            .then(|res| match res {
+                                Ok(Ok(status)) => Ok(status),
+                                Ok(Err(err)) => Err(CommandError::Service(err)),
+                                Err(_) => Err(CommandError::NotReady),
+                            }),
+                    ),
                     None => Response::reply(Err(CommandError::UnknownService)),
                 }
             }
@@ -221,12 +580,12 @@ impl Handler<StatusService> for CommandCenter {
     ) -> Self::Result {
         match self.state {
             State::Running => match self.services.get(&msg.0) {
-                Some(service) => Response::async(service.send(service::Status).then(
-                    |res| match res {
+                Some(service) => Response::async(
+                    service.send(service::Status(true)).then(|res| match res {
                         Ok(Ok(status)) => Ok(status),
                         _ => Err(CommandError::UnknownService),
-                    },
-                )),
+                    }),
+                ),
                 None => Response::reply(Err(CommandError::UnknownService)),
             },
             _ => Response::reply(Err(CommandError::NotReady)),
@@ -306,8 +665,53 @@ impl Handler<ResumeService> for CommandCenter {
     }
 }
 
+/// Change number of workers of the service
+pub struct ScaleService(pub String, pub u16, pub bool);
+
+impl Message for ScaleService {
+    type Result = Result<(), CommandError>;
+}
+
+impl Handler<ScaleService> for CommandCenter {
+    type Result = ActorResponse<CommandCenter, (), CommandError>;
+
+    fn handle(&mut self, msg: ScaleService, _: &mut Context<Self>) -> Self::Result {
+        match self.state {
+            State::Running => {
+                info!("Scale service {:?} to {} workers", msg.0, msg.1);
+                let (name, num, persist) = (msg.0, msg.1, msg.2);
+                match self.services.get(&name) {
+                    Some(service) => ActorResponse::async(
+                        service
+                            .send(service::Scale(num))
+                            .into_actor(self)
+                            .then(move |res, act, _| match res {
+                                Ok(Ok(_)) => {
+                                    if persist {
+                                        act.persisted.set_num(&name, num);
+                                        act.save_state();
+                                    }
+                                    actix::fut::ok(())
+                                }
+                                Ok(Err(err)) => {
+                                    actix::fut::err(CommandError::Service(err))
+                                }
+                                Err(_) => actix::fut::err(CommandError::UnknownService),
+                            }),
+                    ),
+                    None => ActorResponse::reply(Err(CommandError::UnknownService)),
+                }
+            }
+            _ => {
+                warn!("Can not scale service in `{:?}` state", self.state);
+                ActorResponse::reply(Err(CommandError::NotReady))
+            }
+        }
+    }
+}
+
 /// Reload service
-pub struct ReloadService(pub String, pub bool);
+pub struct ReloadService(pub String, pub bool, pub bool);
 
 impl Message for ReloadService {
     type Result = Result<ReloadStatus, CommandError>;
@@ -320,17 +724,17 @@ impl Handler<ReloadService> for CommandCenter {
         match self.state {
             State::Running => {
                 info!("Reloading service {:?}", msg.0);
-                let graceful = msg.1;
+                let (graceful, canary) = (msg.1, msg.2);
                 match self.services.get(&msg.0) {
-                    Some(service) => {
-                        Response::async(service.send(service::Reload(graceful)).then(
-                            |res| match res {
+                    Some(service) => Response::async(
+                        service.send(service::Reload(graceful, canary)).then(|res| {
+                            match res {
                                 Ok(Ok(status)) => Ok(status),
                                 Ok(Err(err)) => Err(CommandError::Service(err)),
                                 Err(_) => Err(CommandError::UnknownService),
-                            },
-                        ))
-                    }
+                            }
+                        }),
+                    ),
                     None => Response::reply(Err(CommandError::UnknownService)),
                 }
             }
@@ -342,6 +746,105 @@ impl Handler<ReloadService> for CommandCenter {
     }
 }
 
+/// Deploy service release, `None` rolls back to previous release
+pub struct DeployService(pub String, pub Option<String>, pub bool);
+
+impl Message for DeployService {
+    type Result = Result<ReloadStatus, CommandError>;
+}
+
+impl Handler<DeployService> for CommandCenter {
+    type Result = ActorResponse<CommandCenter, ReloadStatus, CommandError>;
+
+    fn handle(&mut self, msg: DeployService, _: &mut Context<Self>) -> Self::Result {
+        match self.state {
+            State::Running => {
+                info!("Deploying service {:?} release {:?}", msg.0, msg.1);
+                let (name, release) = (msg.0, msg.1.clone());
+                match self.services.get(&name) {
+                    Some(service) => ActorResponse::async(
+                        service
+                            .send(service::Deploy(msg.1, msg.2))
+                            .into_actor(self)
+                            .then(move |res, act, _| match res {
+                                Ok(Ok(status)) => {
+                                    if let ReloadStatus::Success = status {
+                                        act.release_deployed(&name, release);
+                                    }
+                                    actix::fut::ok(status)
+                                }
+                                Ok(Err(err)) => {
+                                    actix::fut::err(CommandError::Service(err))
+                                }
+                                Err(_) => actix::fut::err(CommandError::UnknownService),
+                            }),
+                    ),
+                    None => ActorResponse::reply(Err(CommandError::UnknownService)),
+                }
+            }
+            _ => {
+                warn!("Can not deploy in system in `{:?}` state", self.state);
+                ActorResponse::reply(Err(CommandError::NotReady))
+            }
+        }
+    }
+}
+
+/// Journal runtime state of the service
+#[derive(Message)]
+pub struct JournalService(pub String, pub ServiceJournal);
+
+impl Handler<JournalService> for CommandCenter {
+    type Result = ();
+
+    fn handle(&mut self, msg: JournalService, ctx: &mut Context<Self>) {
+        self.persisted.set_journal(&msg.0, msg.1);
+
+        // journal changes of all services are saved together
+        if !self.journaled {
+            self.journaled = true;
+            ctx.run_later(JOURNAL_DELAY, |act, _| {
+                if act.journaled {
+                    act.save_state()
+                }
+            });
+        }
+    }
+}
+
+/// Upgrade master binary
+pub struct Upgrade;
+
+impl Message for Upgrade {
+    type Result = Result<(), CommandError>;
+}
+
+impl Handler<Upgrade> for CommandCenter {
+    type Result = ActorResponse<CommandCenter, (), CommandError>;
+
+    fn handle(&mut self, _: Upgrade, _: &mut Context<Self>) -> Self::Result {
+        match self.state {
+            State::Running => ActorResponse::async(self.upgrade()),
+            _ => {
+                warn!("Can not upgrade in system in `{:?}` state", self.state);
+                ActorResponse::reply(Err(CommandError::NotReady))
+            }
+        }
+    }
+}
+
+/// Reopen log files of master process and workers
+#[derive(Message)]
+pub struct ReopenLogs;
+
+impl Handler<ReopenLogs> for CommandCenter {
+    type Result = ();
+
+    fn handle(&mut self, _: ReopenLogs, _: &mut Context<Self>) {
+        self.reopen_logs();
+    }
+}
+
 /// reload all services
 pub struct ReloadAll;
 
@@ -352,13 +855,22 @@ impl Message for ReloadAll {
 impl Handler<ReloadAll> for CommandCenter {
     type Result = ();
 
-    fn handle(&mut self, _: ReloadAll, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, _: ReloadAll, ctx: &mut Context<Self>) -> Self::Result {
         match self.state {
             State::Running => {
                 info!("reloading all services");
-                for srv in self.services.values() {
-                    srv.do_send(service::Reload(true));
-                }
+                notify::notify("RELOADING=1");
+                let reloads: Vec<_> = self
+                    .services
+                    .values()
+                    .map(|srv| srv.send(service::Reload(true, false)))
+                    .collect();
+                future::join_all(reloads)
+                    .into_actor(self)
+                    .then(|_, _, _| {
+                        notify::ready();
+                        actix::fut::ok(())
+                    }).spawn(ctx);
             }
             _ => warn!("Can not reload in system in `{:?}` state", self.state),
         }
@@ -389,54 +901,114 @@ impl Handler<signal::Signal> for CommandCenter {
             }
             signal::SignalType::Child => {
                 info!("SIGCHLD received");
-                debug!("Reap workers");
-                loop {
-                    match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
-                        Ok(WaitStatus::Exited(pid, code)) => {
-                            info!("Worker {} exit code: {}", pid, code);
-                            let err = ProcessError::from(code as i8);
-                            for srv in self.services.values_mut() {
-                                srv.do_send(service::ProcessExited(pid, err.clone()));
-                            }
-                            continue;
-                        }
-                        Ok(WaitStatus::Signaled(pid, sig, _)) => {
-                            info!("Worker {} exit by signal {:?}", pid, sig);
-                            let err = ProcessError::Signal(sig as usize);
-                            for srv in self.services.values_mut() {
-                                srv.do_send(service::ProcessExited(pid, err.clone()));
-                            }
-                            continue;
-                        }
-                        Ok(_) => (),
-                        Err(_) => (),
-                    }
-                    break;
-                }
+                self.reap();
             }
         }
     }
 }
 
+/// Handle SIGUSR1, reopen log files, and SIGUSR2, upgrade master binary
+impl StreamHandler<libc::c_int, io::Error> for CommandCenter {
+    fn handle(&mut self, sig: libc::c_int, ctx: &mut Context<Self>) {
+        if sig == libc::SIGUSR1 {
+            info!("SIGUSR1 received, reopening log files");
+            self.reopen_logs();
+            return;
+        }
+
+        info!("SIGUSR2 received, upgrading");
+        match self.state {
+            State::Running => {
+                self.upgrade()
+                    .map_err(|err, _, _| error!("Can not upgrade master: {:?}", err))
+                    .spawn(ctx);
+            }
+            _ => warn!("Can not upgrade in system in `{:?}` state", self.state),
+        }
+    }
+
+    fn finished(&mut self, _: &mut Context<Self>) {}
+}
+
 impl Actor for CommandCenter {
     type Context = Context<Self>;
 
     fn started(&mut self, ctx: &mut Context<Self>) {
         info!("Starting ctl service: {}", getpid());
 
+        // journal of worker events
+        if let Some(ref path) = self.cfg.master.history {
+            System::current().registry().get::<History>().do_send(history::Open(
+                path.clone(),
+                self.cfg.master.history_max_size,
+            ));
+        }
+
         // listen for process signals
         let addr = ctx.address();
         System::current()
             .registry()
             .get::<signal::ProcessSignals>()
             .do_send(signal::Subscribe(addr.recipient()));
+        ctx.add_stream(
+            tokio_signal::unix::Signal::new(tokio_signal::unix::SIGUSR1)
+                .flatten_stream(),
+        );
+        ctx.add_stream(
+            tokio_signal::unix::Signal::new(tokio_signal::unix::SIGUSR2)
+                .flatten_stream(),
+        );
+
+        // adopt services of previous master process
+        if let Some(state) = self.upgrade.take() {
+            self.adopt(state, ctx);
+        }
+
+        // previous master process did not exit cleanly
+        let crashed = self.persisted.running && self.services.is_empty();
+        if crashed {
+            warn!("Previous master process crashed");
+        }
+        self.persisted.running = true;
+        self.save_state();
 
         // start services
-        for cfg in &self.cfg.services {
-            let service = FeService::start(cfg.num, cfg.clone());
-            self.services.insert(cfg.name.clone(), service);
+        let config = self.cfg.clone();
+        for cfg in &config.services {
+            if self.services.contains_key(&cfg.name) {
+                continue;
+            }
+
+            // workers of crashed master process
+            let pids: Vec<_> = self
+                .persisted
+                .journal(&cfg.name)
+                .workers
+                .iter()
+                .flat_map(|worker| worker.pids.iter().map(|pid| Pid::from_raw(*pid)))
+                .collect();
+            match reaper::Survivors::new(cfg, &pids) {
+                Some(survivors) => {
+                    self.terminating += 1;
+                    self.terminate(cfg.clone(), survivors, crashed, ctx);
+                }
+                None => self.start_service(cfg, crashed, ctx),
+            }
         }
         self.state = State::Running;
+
+        // workers could exit during upgrade
+        self.reap();
+
+        // service manager notifications
+        if notify::enabled() {
+            self.notify_status(ctx);
+            ctx.run_interval(Duration::new(1, 0), |act, ctx| act.notify_status(ctx));
+        }
+        if let Some(interval) = notify::watchdog() {
+            info!("Watchdog is enabled, keep-alive interval: {:?}", interval);
+            ctx.run_interval(interval, |_, _| notify::notify("WATCHDOG=1"));
+        }
     }
 
     fn stopping(&mut self, _: &mut Context<Self>) -> Running {
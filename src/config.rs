@@ -1,15 +1,21 @@
 use std;
+use std::collections::HashMap;
 use std::error::Error;
-use std::ffi::OsString;
+use std::ffi::{OsStr, OsString};
 use std::io::prelude::*;
+use std::io::SeekFrom;
+use std::os::unix::io::AsRawFd;
 use std::path::Path;
 
 use nix;
+use nix::fcntl::{flock, FlockArg};
+use nix::sys::signal::Signal;
 use nix::unistd::{Gid, Uid};
 use structopt::StructOpt;
 use toml;
 
 use config_helpers;
+use restart::RestartPolicy;
 use socket;
 
 pub struct Config {
@@ -25,7 +31,11 @@ pub struct Config {
 /// [master]
 /// daemon = true
 /// pid = "fectl.pid"
+/// lock = "fectl.lock"
 /// sock = "fectl.sock"
+/// state = "fectl.state"
+/// history = "fectl.history"
+/// history_max_size = "10M"
 /// directory = "/path/to/dir"
 /// ```
 #[derive(Debug)]
@@ -34,8 +44,16 @@ pub struct MasterConfig {
     pub daemon: bool,
     /// Path to file with process pid
     pub pid: Option<OsString>,
+    /// Path to lock file, only one master process could hold the lock
+    pub lock: OsString,
     /// Path to controller unix domain socket
     pub sock: OsString,
+    /// Path to file with persisted master state
+    pub state: OsString,
+    /// Path to journal of worker events
+    pub history: Option<OsString>,
+    /// Journal is rotated once it grows over this size
+    pub history_max_size: u64,
     /// Change to specified directory before apps loading.
     pub directory: OsString,
 
@@ -53,8 +71,12 @@ pub struct MasterConfig {
 impl MasterConfig {
     /// remove pid and sock files
     pub fn remove_files(&self) {
+        // lock file is never removed, otherwise next master process could
+        // lock new file while running master holds lock of unlinked one
         if let Some(ref pid) = self.pid {
-            let _ = std::fs::remove_file(pid);
+            if *pid != self.lock {
+                let _ = std::fs::remove_file(pid);
+            }
         }
         let _ = std::fs::remove_file(&self.sock);
     }
@@ -62,17 +84,42 @@ impl MasterConfig {
     /// load pid of the master process
     pub fn load_pid(&self) -> Option<nix::unistd::Pid> {
         if let Some(ref pid) = self.pid {
-            if let Ok(mut file) = std::fs::File::open(pid) {
-                let mut buf = Vec::new();
-                if file.read_to_end(&mut buf).is_ok() {
-                    let spid = String::from_utf8_lossy(buf.as_ref());
-                    if let Ok(pid) = spid.parse::<i32>() {
-                        return Some(nix::unistd::Pid::from_raw(pid));
-                    }
+            read_pid(pid)
+        } else {
+            None
+        }
+    }
+
+    /// Acquire lock file, lock is held until returned file is closed
+    pub fn lock(&self) -> Result<std::fs::File, std::io::Error> {
+        let file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&self.lock)?;
+
+        if let Err(err) = flock(file.as_raw_fd(), FlockArg::LockExclusiveNonblock) {
+            return Err(match err {
+                nix::Error::Sys(errno) => {
+                    std::io::Error::from_raw_os_error(errno as i32)
                 }
-            }
+                err => std::io::Error::new(std::io::ErrorKind::Other, err),
+            });
         }
-        None
+        Ok(file)
+    }
+
+    /// Store pid of the master process in lock file
+    pub fn save_lock_pid(&self, mut file: &std::fs::File) -> Result<(), std::io::Error> {
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(nix::unistd::getpid().to_string().as_ref())
+    }
+
+    /// load pid of the master process that holds lock
+    pub fn load_lock_pid(&self) -> Option<nix::unistd::Pid> {
+        read_pid(&self.lock)
     }
 
     /// save pid to filesystem
@@ -85,6 +132,19 @@ impl MasterConfig {
     }
 }
 
+fn read_pid(path: &OsStr) -> Option<nix::unistd::Pid> {
+    if let Ok(mut file) = std::fs::File::open(path) {
+        let mut buf = Vec::new();
+        if file.read_to_end(&mut buf).is_ok() {
+            let spid = String::from_utf8_lossy(buf.as_ref());
+            if let Ok(pid) = spid.parse::<i32>() {
+                return Some(nix::unistd::Pid::from_raw(pid));
+            }
+        }
+    }
+    None
+}
+
 #[derive(Deserialize, Debug)]
 struct TomlConfig {
     master: Option<TomlMasterConfig>,
@@ -99,7 +159,13 @@ struct TomlConfig {
 struct TomlMasterConfig {
     #[serde(default = "config_helpers::default_sock")]
     pub sock: String,
+    pub state: Option<String>,
     pub pid: Option<String>,
+    pub lock: Option<String>,
+    pub history: Option<String>,
+    #[serde(default)]
+    #[serde(deserialize_with = "config_helpers::deserialize_opt_size")]
+    pub history_max_size: Option<u64>,
     pub directory: Option<String>,
 
     #[serde(default)]
@@ -164,6 +230,119 @@ pub struct ServiceConfig {
     #[serde(default = "config_helpers::default_restarts")]
     pub restarts: u16,
 
+    /// Restart policy for exited workers, default `always`
+    ///
+    /// Possible values are `always`, `on-failure`, `never` and `unless-stopped`.
+    #[serde(default = "config_helpers::default_restart")]
+    pub restart: RestartPolicy,
+
+    /// Exit codes that are considered successful, default `[0]`
+    ///
+    /// Workers that exit with one of these codes are not restarted
+    /// with `on-failure` restart policy.
+    #[serde(default = "config_helpers::default_success_exit_codes")]
+    pub success_exit_codes: Vec<i32>,
+
+    /// Maximum number of worker restarts within `start_limit_interval`, default 5
+    ///
+    /// Worker that is restarted more often is marked as failed. `0` disables limit.
+    #[serde(default = "config_helpers::default_start_limit_burst")]
+    pub start_limit_burst: u16,
+
+    /// Start limit interval in seconds, default 10
+    #[serde(default = "config_helpers::default_start_limit_interval")]
+    pub start_limit_interval: u32,
+
+    /// Delay in seconds before restarting failed worker, default 1
+    #[serde(default = "config_helpers::default_restart_delay")]
+    pub restart_delay: u32,
+
+    /// Maximum delay in seconds between restarts of failed worker, default 60
+    #[serde(default = "config_helpers::default_restart_delay_max")]
+    pub restart_delay_max: u32,
+
+    /// Restart delay grows by this factor after each failed restart, default 2.0
+    #[serde(default = "config_helpers::default_restart_delay_multiplier")]
+    pub restart_delay_multiplier: f64,
+
+    /// Random jitter applied to restart delay, fraction of the delay, default 0.2
+    ///
+    /// Jitter prevents workers of the same service from restarting in lockstep.
+    #[serde(default = "config_helpers::default_restart_jitter")]
+    pub restart_jitter: f64,
+
+    /// Restart backoff is reset after worker runs for this many seconds, default 10
+    #[serde(default = "config_helpers::default_restart_stable_period")]
+    pub restart_stable_period: u32,
+
+    /// Try to start failed service or failed workers after this many seconds.
+    ///
+    /// By default failed services are not recovered automatically.
+    pub failed_retry_after: Option<u32>,
+
+    /// Maximum number of consecutive recovery attempts, default 3
+    #[serde(default = "config_helpers::default_failed_retry_max")]
+    pub failed_retry_max: u16,
+
+    /// Number of workers that get reloaded at the same time
+    ///
+    /// Each reloaded worker runs old and new process side by side until new
+    /// process is loaded. By default all workers are reloaded at once.
+    #[serde(alias = "max_surge")]
+    pub reload_batch_size: Option<u16>,
+
+    /// Number of workers that get stopped before new process starts, default 0
+    ///
+    /// These workers are reloaded in addition to `reload_batch_size` workers,
+    /// and do not require additional memory during reload.
+    #[serde(default)]
+    pub max_unavailable: u16,
+
+    /// Pause in seconds between reload batches, default 0
+    #[serde(default)]
+    pub reload_pause: u32,
+
+    /// Canary worker soak period in seconds, default 30
+    ///
+    /// With `fectl reload --canary` one worker is reloaded first, it has to stay
+    /// loaded for this many seconds before remaining workers get reloaded.
+    #[serde(default = "config_helpers::default_canary_period")]
+    pub canary_period: u32,
+
+    /// Health probe command, executed with `/bin/sh -c` after soak period
+    ///
+    /// Canary fails if probe exits with non zero code.
+    pub canary_probe: Option<String>,
+
+    /// Timeout for health probe in seconds, default 10
+    #[serde(default = "config_helpers::default_canary_probe_timeout")]
+    pub canary_probe_timeout: u32,
+
+    /// Minimum number of workers for autoscaling, default 1
+    #[serde(default = "config_helpers::default_min_workers")]
+    pub min_workers: u16,
+
+    /// Maximum number of workers for autoscaling
+    ///
+    /// Autoscaling is enabled only if this option is set.
+    pub max_workers: Option<u16>,
+
+    /// Add workers if average worker load is above this value, default 0.8
+    #[serde(default = "config_helpers::default_scale_up_threshold")]
+    pub scale_up_threshold: f64,
+
+    /// Remove workers if average worker load is below this value, default 0.3
+    #[serde(default = "config_helpers::default_scale_down_threshold")]
+    pub scale_down_threshold: f64,
+
+    /// Minimum number of seconds between two scaling actions, default 60
+    #[serde(default = "config_helpers::default_scale_cooldown")]
+    pub scale_cooldown: u32,
+
+    /// Check worker load every this many seconds, default 5
+    #[serde(default = "config_helpers::default_scale_interval")]
+    pub scale_interval: u32,
+
     /// Change to specified directory before service worker loading.
     pub directory: Option<String>,
 
@@ -210,6 +389,38 @@ pub struct ServiceConfig {
     #[serde(default = "config_helpers::default_shutdown_timeout")]
     pub shutdown_timeout: u32,
 
+    /// Signal that gracefully stops worker, default `TERM`
+    ///
+    /// Used only if `kill_escalation` is not set.
+    #[serde(default = "config_helpers::default_stop_signal")]
+    #[serde(deserialize_with = "config_helpers::deserialize_signal")]
+    pub stop_signal: Signal,
+
+    /// Signal for old worker process during graceful reload.
+    ///
+    /// Replaces signal of the first step of stop sequence.
+    #[serde(default)]
+    #[serde(deserialize_with = "config_helpers::deserialize_opt_signal")]
+    pub reload_signal: Option<Signal>,
+
+    /// Stop sequence, i.e. `["TERM:30s", "INT:5s", "KILL"]`
+    ///
+    /// Each step sends signal to worker and waits for worker exit for
+    /// specified number of seconds, one second by default. Worker that is still
+    /// alive after last step gets killed. By default `stop_signal` is sent and
+    /// worker is killed after `shutdown_timeout`.
+    #[serde(default)]
+    #[serde(deserialize_with = "config_helpers::deserialize_kill_steps")]
+    pub kill_escalation: Vec<KillStep>,
+
+    /// Signal delivery mode, default `group`
+    ///
+    /// With `group` mode each worker runs in its own session and signals are
+    /// sent to the whole process group, so worker subprocesses are stopped
+    /// together with worker. With `process` mode only worker process gets signals.
+    #[serde(default = "config_helpers::default_kill_mode")]
+    pub kill_mode: KillMode,
+
     /// A path to a file where `fectld` should redirect `stdout` for this service.
     ///
     /// By default redirect for stdout is not enabled
@@ -219,21 +430,164 @@ pub struct ServiceConfig {
     ///
     /// By default redirect for stderr is not enabled
     pub stderr: Option<String>,
+
+    /// Capture worker `stdout` and `stderr` through pipes, default false
+    ///
+    /// Master reads captured output line by line and routes it to `output` sink,
+    /// `stdout` and `stderr` settings are ignored.
+    #[serde(default)]
+    pub capture_output: bool,
+
+    /// Destination of captured output: `log`, `buffer` or path to a file
+    ///
+    /// `log` writes lines to master log, `buffer` keeps last `output_buffer`
+    /// lines in memory. By default lines are written to master log.
+    #[serde(default)]
+    #[serde(deserialize_with = "config_helpers::deserialize_output_sink")]
+    pub output: OutputSink,
+
+    /// Number of lines kept in output buffer, default 1000
+    #[serde(default = "config_helpers::default_output_buffer")]
+    pub output_buffer: usize,
+
+    /// Rotate output file when it grows over this size, i.e. `10M`
+    #[serde(default)]
+    #[serde(deserialize_with = "config_helpers::deserialize_opt_size")]
+    pub output_max_size: Option<u64>,
+
+    /// Rotate output file every hour or every day, `hourly` or `daily`
+    pub output_rotate: Option<RotateInterval>,
+
+    /// Number of rotated output files to keep, default 5
+    #[serde(default = "config_helpers::default_output_keep")]
+    pub output_keep: usize,
+
+    /// Compress rotated output files with `gzip`, default false
+    #[serde(default)]
+    pub output_compress: bool,
+
+    /// Named releases of the service
+    ///
+    /// ```toml
+    /// [service.release.v42]
+    /// directory = "/srv/app/v42"
+    /// command = "python -m app"
+    /// ```
+    #[serde(default)]
+    pub release: HashMap<String, ReleaseConfig>,
+}
+
+impl ServiceConfig {
+    /// Signals sequence for stopping worker process
+    pub fn stop_sequence(&self, reload: bool) -> Vec<KillStep> {
+        let mut steps = if self.kill_escalation.is_empty() {
+            vec![KillStep {
+                signal: self.stop_signal,
+                timeout: self.shutdown_timeout,
+            }]
+        } else {
+            self.kill_escalation.clone()
+        };
+        if let (true, Some(signal)) = (reload, self.reload_signal) {
+            steps[0].signal = signal;
+        }
+        steps
+    }
+
+    /// Service config with `command` and `directory` of the release
+    pub fn with_release(&self, release: Option<&String>) -> ServiceConfig {
+        let mut cfg = self.clone();
+        if let Some(rel) = release.and_then(|name| self.release.get(name)) {
+            if let Some(ref command) = rel.command {
+                cfg.command = command.clone();
+            }
+            if let Some(ref directory) = rel.directory {
+                cfg.directory = Some(directory.clone());
+            }
+        }
+        cfg
+    }
+}
+
+/// Signal delivery mode
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum KillMode {
+    /// Signal worker process only
+    Process,
+    /// Signal process group of the worker, including worker subprocesses
+    Group,
+}
+
+/// Destination of captured worker output
+#[derive(Clone, Debug, PartialEq)]
+pub enum OutputSink {
+    /// Master log
+    Log,
+    /// Ring buffer of the service
+    Buffer,
+    /// Append to file
+    File(String),
+}
+
+impl Default for OutputSink {
+    fn default() -> OutputSink {
+        OutputSink::Log
+    }
+}
+
+/// Time based rotation of output file
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum RotateInterval {
+    Hourly,
+    Daily,
+}
+
+/// Step of worker stop sequence
+#[derive(Clone, Debug)]
+pub struct KillStep {
+    /// Signal to send
+    pub signal: Signal,
+
+    /// Number of seconds to wait for worker exit
+    pub timeout: u32,
+}
+
+/// Service release, overrides service settings
+#[derive(Deserialize, Clone, Debug)]
+pub struct ReleaseConfig {
+    /// Worker start command
+    pub command: Option<String>,
+
+    /// Worker directory
+    pub directory: Option<String>,
 }
 
 /// Loging configuration
 ///
 /// ```toml
 /// [logging]
+/// name = "fectld"
+/// service = "syslog"
 /// level = "info"
 /// facility = "user"
+/// address = "/dev/log"
+/// format = "text"
 /// ```
 #[derive(Deserialize, Debug)]
 pub struct LoggingConfig {
     pub name: String,
+    /// Logging backend, `console`, `syslog` or `journald`
     pub service: String,
     pub level: Option<String>,
+    /// Syslog facility, default `user`
     pub facility: Option<String>,
+    /// Syslog server, unix socket path, `udp://host:port` or `tcp://host:port`,
+    /// journal socket path for `journald`
+    pub address: Option<String>,
+    /// Console output format, `text` or `json`
+    pub format: Option<String>,
 }
 
 impl Default for LoggingConfig {
@@ -243,6 +597,8 @@ impl Default for LoggingConfig {
             service: "console".to_owned(),
             level: Some("info".to_owned()),
             facility: None,
+            address: None,
+            format: None,
         }
     }
 }
@@ -284,8 +640,12 @@ pub fn load_config() -> Option<Config> {
     // master config
     let toml_master = cfg.master.unwrap_or(TomlMasterConfig {
         sock: config_helpers::default_sock(),
+        state: None,
         directory: None,
         pid: None,
+        lock: None,
+        history: None,
+        history_max_size: None,
         gid: None,
         uid: None,
         stdout: None,
@@ -315,6 +675,28 @@ pub fn load_config() -> Option<Config> {
         None
     };
 
+    // state file is next to pid file, i.e. `fectld.state` for `fectld.pid`
+    let state = if let Some(ref state) = toml_master.state {
+        Path::new(&directory).join(state).into_os_string()
+    } else if let Some(ref pid) = pid {
+        Path::new(pid).with_extension("state").into_os_string()
+    } else {
+        Path::new(&directory)
+            .join(config_helpers::default_state())
+            .into_os_string()
+    };
+
+    // lock file is next to socket, pid file is removed on exit
+    let lock = if let Some(ref lock) = toml_master.lock {
+        Path::new(&directory).join(lock).into_os_string()
+    } else {
+        let mut lock = Path::new(&directory)
+            .join(&toml_master.sock)
+            .into_os_string();
+        lock.push(".lock");
+        lock
+    };
+
     let master = MasterConfig {
         // set default value from command line
         daemon: args.daemon,
@@ -324,7 +706,15 @@ pub fn load_config() -> Option<Config> {
             .join(&toml_master.sock)
             .into_os_string(),
 
+        // canonizalize history file path
+        history: toml_master
+            .history
+            .map(|history| Path::new(&directory).join(history).into_os_string()),
+        history_max_size: toml_master.history_max_size.unwrap_or(10 * 1024 * 1024),
+
         pid,
+        lock,
+        state,
         gid: toml_master.gid,
         uid: toml_master.uid,
 
@@ -336,6 +726,33 @@ pub fn load_config() -> Option<Config> {
         stderr: toml_master.stderr,
     };
 
+    // check autoscaling and reload settings
+    for srv in &cfg.service {
+        if srv.reload_batch_size == Some(0) && srv.max_unavailable == 0 {
+            println!(
+                "Service {:?}: reload_batch_size or max_unavailable has to be positive",
+                srv.name
+            );
+            return None;
+        }
+        if let Some(max) = srv.max_workers {
+            if srv.min_workers > max {
+                println!(
+                    "Service {:?}: min_workers is greater than max_workers",
+                    srv.name
+                );
+                return None;
+            }
+            if srv.scale_down_threshold >= srv.scale_up_threshold {
+                println!(
+                    "Service {:?}: scale_down_threshold has to be less than scale_up_threshold",
+                    srv.name
+                );
+                return None;
+            }
+        }
+    }
+
     // sockets config
     let sockets = match socket::Socket::load_config(&cfg.socket) {
         Ok(sockets) => sockets,
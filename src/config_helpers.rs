@@ -1,11 +1,14 @@
 use std::ffi::CString;
+use std::str::FromStr;
 
 use libc;
+use nix::sys::signal::Signal;
 use nix::unistd::{Gid, Uid};
 use serde;
 use serde_json as json;
 
-use config::Proto;
+use config::{KillMode, KillStep, OutputSink, Proto};
+use restart::RestartPolicy;
 
 pub fn default_vec<T>() -> Vec<T> {
     Vec::new()
@@ -15,6 +18,10 @@ pub fn default_sock() -> String {
     "fectld.sock".to_owned()
 }
 
+pub fn default_state() -> String {
+    "fectld.state".to_owned()
+}
+
 pub fn default_backlog() -> u16 {
     256
 }
@@ -27,6 +34,74 @@ pub fn default_restarts() -> u16 {
     3
 }
 
+pub fn default_restart() -> RestartPolicy {
+    RestartPolicy::Always
+}
+
+pub fn default_success_exit_codes() -> Vec<i32> {
+    vec![0]
+}
+
+pub fn default_start_limit_burst() -> u16 {
+    5
+}
+
+pub fn default_start_limit_interval() -> u32 {
+    10
+}
+
+pub fn default_failed_retry_max() -> u16 {
+    3
+}
+
+pub fn default_canary_period() -> u32 {
+    30
+}
+
+pub fn default_canary_probe_timeout() -> u32 {
+    10
+}
+
+pub fn default_min_workers() -> u16 {
+    1
+}
+
+pub fn default_scale_up_threshold() -> f64 {
+    0.8
+}
+
+pub fn default_scale_down_threshold() -> f64 {
+    0.3
+}
+
+pub fn default_scale_cooldown() -> u32 {
+    60
+}
+
+pub fn default_scale_interval() -> u32 {
+    5
+}
+
+pub fn default_restart_delay() -> u32 {
+    1
+}
+
+pub fn default_restart_delay_max() -> u32 {
+    60
+}
+
+pub fn default_restart_delay_multiplier() -> f64 {
+    2.0
+}
+
+pub fn default_restart_jitter() -> f64 {
+    0.2
+}
+
+pub fn default_restart_stable_period() -> u32 {
+    10
+}
+
 pub fn default_timeout() -> u32 {
     10
 }
@@ -39,6 +114,128 @@ pub fn default_shutdown_timeout() -> u32 {
     30
 }
 
+pub fn default_kill_mode() -> KillMode {
+    KillMode::Group
+}
+
+pub fn default_stop_signal() -> Signal {
+    Signal::SIGTERM
+}
+
+pub fn default_output_buffer() -> usize {
+    1000
+}
+
+pub fn default_output_keep() -> usize {
+    5
+}
+
+/// Parse signal name, `SIG` prefix is optional
+fn parse_signal(name: &str) -> Result<Signal, String> {
+    let name = name.trim().to_uppercase();
+    let name = if name.starts_with("SIG") {
+        name
+    } else {
+        format!("SIG{}", name)
+    };
+    Signal::from_str(&name).map_err(|_| format!("Unknown signal: {}", name))
+}
+
+/// Parse stop sequence step, i.e. `TERM:30s` or `KILL`
+fn parse_kill_step(step: &str) -> Result<KillStep, String> {
+    let mut parts = step.splitn(2, ':');
+    let signal = parse_signal(parts.next().unwrap_or(""))?;
+    let timeout = match parts.next() {
+        Some(timeout) => {
+            let timeout = timeout.trim();
+            let timeout = timeout.trim_end_matches('s');
+            timeout
+                .parse::<u32>()
+                .map_err(|_| format!("Can not parse step timeout: {}", step))?
+        }
+        None => 1,
+    };
+    Ok(KillStep { signal, timeout })
+}
+
+/// Deserialize signal name into `Signal`
+pub fn deserialize_signal<'de, D>(de: D) -> Result<Signal, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let name: String = serde::Deserialize::deserialize(de)?;
+    parse_signal(&name).map_err(serde::de::Error::custom)
+}
+
+/// Deserialize optional signal name into `Signal`
+pub fn deserialize_opt_signal<'de, D>(de: D) -> Result<Option<Signal>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_signal(de).map(Some)
+}
+
+/// Deserialize stop sequence, i.e. `["TERM:30s", "INT:5s", "KILL"]`
+pub fn deserialize_kill_steps<'de, D>(de: D) -> Result<Vec<KillStep>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let steps: Vec<String> = serde::Deserialize::deserialize(de)?;
+    steps
+        .iter()
+        .map(|step| parse_kill_step(step).map_err(serde::de::Error::custom))
+        .collect()
+}
+
+/// Deserialize output sink, `log`, `buffer` or path to a file
+pub fn deserialize_output_sink<'de, D>(de: D) -> Result<OutputSink, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let sink: String = serde::Deserialize::deserialize(de)?;
+    match sink.trim() {
+        "log" => Ok(OutputSink::Log),
+        "buffer" => Ok(OutputSink::Buffer),
+        "" => Err(serde::de::Error::custom("Output sink is empty")),
+        path => Ok(OutputSink::File(path.to_owned())),
+    }
+}
+
+/// Parse size with optional `K`, `M` or `G` suffix, i.e. `10M`
+fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim().to_uppercase();
+    let size = size.trim_end_matches('B');
+    let (num, mult) = match size.chars().last() {
+        Some('K') => (&size[..size.len() - 1], 1 << 10),
+        Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size, 1),
+    };
+    num.trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|num| num.checked_mul(mult))
+        .ok_or_else(|| format!("Can not parse size: {}", size))
+}
+
+/// Deserialize optional size, number of bytes or string with suffix
+pub fn deserialize_opt_size<'de, D>(de: D) -> Result<Option<u64>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    let deser_result: json::Value = serde::Deserialize::deserialize(de)?;
+    match deser_result {
+        json::Value::String(ref s) => {
+            parse_size(s).map(Some).map_err(serde::de::Error::custom)
+        }
+        json::Value::Number(num) => match num.as_u64() {
+            Some(num) => Ok(Some(num)),
+            None => Err(serde::de::Error::custom("Size has to be positive integer")),
+        },
+        _ => Err(serde::de::Error::custom("Unexpected value")),
+    }
+}
+
 /// Deserialize `gid` field into `Gid`
 pub(crate) fn deserialize_gid_field<'de, D>(de: D) -> Result<Option<Gid>, D::Error>
 where
@@ -104,3 +301,50 @@ where
     }
     Err(serde::de::Error::custom("Unexpected value"))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_signal() {
+        assert_eq!(parse_signal("TERM"), Ok(Signal::SIGTERM));
+        assert_eq!(parse_signal("SIGINT"), Ok(Signal::SIGINT));
+        assert_eq!(parse_signal(" quit "), Ok(Signal::SIGQUIT));
+        assert!(parse_signal("UNKNOWN").is_err());
+        assert!(parse_signal("").is_err());
+    }
+
+    #[test]
+    fn test_parse_kill_step() {
+        let step = parse_kill_step("TERM:30s").unwrap();
+        assert_eq!(step.signal, Signal::SIGTERM);
+        assert_eq!(step.timeout, 30);
+
+        let step = parse_kill_step("INT: 5").unwrap();
+        assert_eq!(step.signal, Signal::SIGINT);
+        assert_eq!(step.timeout, 5);
+
+        let step = parse_kill_step("KILL").unwrap();
+        assert_eq!(step.signal, Signal::SIGKILL);
+        assert_eq!(step.timeout, 1);
+
+        assert!(parse_kill_step("TERM:soon").is_err());
+        assert!(parse_kill_step("NOPE:5s").is_err());
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("100"), Ok(100));
+        assert_eq!(parse_size("10K"), Ok(10 * 1024));
+        assert_eq!(parse_size("10kb"), Ok(10 * 1024));
+        assert_eq!(parse_size(" 5 M "), Ok(5 * 1024 * 1024));
+        assert_eq!(parse_size("2GB"), Ok(2 * 1024 * 1024 * 1024));
+        assert_eq!(parse_size("0"), Ok(0));
+        assert!(parse_size("").is_err());
+        assert!(parse_size("M").is_err());
+        assert!(parse_size("-1K").is_err());
+        assert!(parse_size("10T").is_err());
+        assert!(parse_size("99999999999999G").is_err());
+    }
+}
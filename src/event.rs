@@ -2,9 +2,23 @@ use std;
 use std::collections::VecDeque;
 use std::time::{SystemTime, UNIX_EPOCH};
 
-pub type ServiceStatus = (String, Vec<(String, Vec<Event>)>);
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceStatus {
+    /// Service state
+    pub status: String,
+    /// Number of workers
+    pub workers: usize,
+    /// Active release
+    #[serde(default)]
+    pub release: Option<String>,
+    /// Orphaned worker subprocesses
+    #[serde(default)]
+    pub orphans: Vec<String>,
+    /// Events of each worker
+    pub events: Vec<(String, Vec<Event>)>,
+}
 
-#[derive(Clone, Copy, Serialize, Deserialize, Debug)]
+#[derive(Clone, Copy, Serialize, Deserialize, Debug, PartialEq)]
 pub enum State {
     Starting,
     Reloading,
@@ -41,6 +55,12 @@ pub enum Reason {
     RestartFailedRunningWorker,
     RestoreAftreFailed,
     ReloadAftreTimeout,
+    StartLimitReached,
+    AutoRecovery,
+    Autoscale,
+    CanaryFailed,
+    StopSignal(String),
+    MasterUpgrade,
 }
 
 #[derive(Clone, Serialize, Deserialize, Debug)]
@@ -81,10 +101,25 @@ impl Events {
 
     /// Add new event
     pub fn add(&mut self, state: State, reason: Reason, pid: Option<String>) {
+        self.push(Event::new(state, reason, pid));
+    }
+
+    /// Add existing event
+    pub fn push(&mut self, event: Event) {
         if self.events.len() >= self.max {
             self.events.pop_front();
         }
-        self.events.push_back(Event::new(state, reason, pid));
+        self.events.push_back(event);
+    }
+
+    /// Restore events, i.e. handed over by previous master process
+    pub fn extend(&mut self, events: Vec<Event>) {
+        for event in events {
+            if self.events.len() >= self.max {
+                self.events.pop_front();
+            }
+            self.events.push_back(event);
+        }
     }
 }
 
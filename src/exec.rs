@@ -6,10 +6,10 @@ use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use bytes::{Buf, BufMut, BytesMut, IntoBuf};
 use libc;
-use nix::unistd::{chdir, dup2, execve, setgid, setuid};
+use nix::unistd::{chdir, close, dup2, execve, setgid, setsid, setuid};
 use serde_json as json;
 
-use config::ServiceConfig;
+use config::{KillMode, ServiceConfig};
 use process::{WORKER_BOOT_FAILED, WORKER_INIT_FAILED};
 use utils;
 use worker::{WorkerCommand, WorkerMessage};
@@ -27,7 +27,17 @@ fn send_msg(file: &mut std::fs::File, msg: WorkerMessage) {
     }
 }
 
-pub fn exec_worker(idx: usize, cfg: &ServiceConfig, read: RawFd, write: RawFd) {
+pub fn exec_worker(
+    idx: usize, cfg: &ServiceConfig, read: RawFd, write: RawFd,
+    output: Option<(RawFd, RawFd)>,
+) {
+    // start new session, worker becomes leader of new process group
+    if cfg.kill_mode == KillMode::Group {
+        if let Err(err) = setsid() {
+            error!("Can not create new session: {}", err);
+        }
+    }
+
     // notify master
     let mut file = unsafe { std::fs::File::from_raw_fd(write) };
     send_msg(&mut file, WorkerMessage::forked);
@@ -112,8 +122,16 @@ pub fn exec_worker(idx: usize, cfg: &ServiceConfig, read: RawFd, write: RawFd) {
     let mut args: Vec<_> = vec![CString::new(path.as_str()).unwrap()];
     args.extend(iter.map(|s| CString::new(s).unwrap()).collect::<Vec<_>>());
 
+    // redirect stdout and stderr to master pipes
+    if let Some((stdout, stderr)) = output {
+        let _ = dup2(stdout, libc::STDOUT_FILENO);
+        let _ = dup2(stderr, libc::STDERR_FILENO);
+        let _ = close(stdout);
+        let _ = close(stderr);
+    }
+
     // redirect stdout and stderr
-    if let Some(ref stdout) = cfg.stdout {
+    if let (None, Some(stdout)) = (output, cfg.stdout.as_ref()) {
         match std::fs::OpenOptions::new()
             .append(true)
             .create(true)
@@ -135,7 +153,7 @@ pub fn exec_worker(idx: usize, cfg: &ServiceConfig, read: RawFd, write: RawFd) {
         }
     }
 
-    if let Some(ref stderr) = cfg.stderr {
+    if let (None, Some(stderr)) = (output, cfg.stderr.as_ref()) {
         match std::fs::OpenOptions::new()
             .append(true)
             .create(true)
@@ -163,6 +181,16 @@ pub fn exec_worker(idx: usize, cfg: &ServiceConfig, read: RawFd, write: RawFd) {
     env.push(CString::new(format!("FECTL_FD={}:{}", read, write)).unwrap());
     env.push(CString::new(format!("FECTL_SRV_NAME={}", cfg.name)).unwrap());
     env.push(CString::new(format!("FECTL_PROC_IDX={}", idx)).unwrap());
+
+    // worker reopens redirect files on `reopen` command
+    if output.is_none() {
+        if let Some(ref stdout) = cfg.stdout {
+            env.push(CString::new(format!("FECTL_STDOUT={}", stdout)).unwrap());
+        }
+        if let Some(ref stderr) = cfg.stderr {
+            env.push(CString::new(format!("FECTL_STDERR={}", stderr)).unwrap());
+        }
+    }
     match execve(&CString::new(path).unwrap(), &args, &env) {
         Ok(_) => unreachable!(),
         Err(err) => {
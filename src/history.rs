@@ -0,0 +1,469 @@
+// Persistent journal of worker events, survives master restarts,
+// and live event subscriptions
+use std;
+use std::collections::VecDeque;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, BufRead, BufReader, Write};
+use std::thread;
+
+use actix::prelude::*;
+use futures::sync::oneshot;
+use futures::{future, Future};
+use serde_json as json;
+
+use event::{Event, State};
+use master_types::HistoryRecord;
+
+/// Maximum number of records returned by query, newest records are returned
+const MAX_RECORDS: usize = 1000;
+
+/// Journal of worker events, JSON record per line
+///
+/// Journal file is rotated to `<history>.1` once it grows over max size,
+/// so journal takes at most twice of max size on disk. Events are also
+/// sent to subscribers, regardless of journal.
+#[derive(Default)]
+pub struct History {
+    journal: Option<Journal>,
+    subscribers: Vec<Subscriber>,
+}
+
+struct Journal {
+    path: OsString,
+    file: std::fs::File,
+    size: u64,
+    max_size: u64,
+}
+
+impl Journal {
+    fn open(path: &OsStr, max_size: u64) -> Result<Journal, io::Error> {
+        let file = Journal::open_file(path)?;
+        Ok(Journal {
+            size: file.metadata()?.len(),
+            path: path.to_owned(),
+            file,
+            max_size,
+        })
+    }
+
+    fn open_file(path: &OsStr) -> Result<std::fs::File, io::Error> {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+    }
+
+    // previous journal file
+    fn rotated(&self) -> OsString {
+        let mut path = self.path.clone();
+        path.push(".1");
+        path
+    }
+
+    fn write(&mut self, rec: &HistoryRecord) -> Result<(), io::Error> {
+        let mut line = json::to_string(rec)?;
+        line.push('\n');
+
+        if self.size > 0 && self.size + line.len() as u64 > self.max_size {
+            std::fs::rename(&self.path, self.rotated())?;
+            self.file = Journal::open_file(&self.path)?;
+            self.size = 0;
+        }
+        self.file.write_all(line.as_ref())?;
+        self.size += line.len() as u64;
+        Ok(())
+    }
+
+    // open rotated and current journal files, oldest first
+    //
+    // Files are opened by actor, so rotation can not happen in between
+    // and reader is not affected by later rotations.
+    fn open_files(&self) -> Result<Vec<(OsString, std::fs::File)>, io::Error> {
+        let mut files = Vec::new();
+        for path in &[self.rotated(), self.path.clone()] {
+            match std::fs::File::open(path) {
+                Ok(file) => files.push((path.clone(), file)),
+                Err(ref err) if err.kind() == io::ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(files)
+    }
+}
+
+// newest matching records of journal files, at most `MAX_RECORDS`
+fn read<F>(
+    files: Vec<(OsString, std::fs::File)>, matches: F,
+) -> Result<Vec<HistoryRecord>, io::Error>
+where
+    F: Fn(&HistoryRecord) -> bool,
+{
+    let mut records = VecDeque::new();
+    for (path, file) in files {
+        for line in BufReader::new(file).lines() {
+            let line = line.map_err(|err| {
+                error!("Can not read history file {:?}: {}", path, err);
+                err
+            })?;
+            // partially written record is skipped
+            if let Ok(rec) = json::from_str(&line) {
+                if matches(&rec) {
+                    if records.len() == MAX_RECORDS {
+                        records.pop_front();
+                    }
+                    records.push_back(rec);
+                }
+            }
+        }
+    }
+    Ok(records.into_iter().collect())
+}
+
+impl History {
+    // subscribers are removed once client connection is closed
+    fn publish(&mut self, rec: &HistoryRecord) {
+        self.subscribers.retain(|sub| {
+            !sub.matches(rec) || sub.rcp.do_send(WorkerEvent(rec.clone())).is_ok()
+        });
+    }
+}
+
+impl Actor for History {
+    type Context = Context<Self>;
+}
+
+impl Supervised for History {}
+
+impl SystemService for History {}
+
+/// Open journal file, history is not recorded until journal is opened
+#[derive(Message)]
+pub struct Open(pub OsString, pub u64);
+
+impl Handler<Open> for History {
+    type Result = ();
+
+    fn handle(&mut self, msg: Open, _: &mut Context<Self>) {
+        match Journal::open(&msg.0, msg.1) {
+            Ok(journal) => self.journal = Some(journal),
+            Err(err) => error!("Can not open history file {:?}: {}", msg.0, err),
+        }
+    }
+}
+
+/// Record worker event
+#[derive(Message)]
+pub struct Record(pub String, pub usize, pub Event);
+
+impl Handler<Record> for History {
+    type Result = ();
+
+    fn handle(&mut self, msg: Record, _: &mut Context<Self>) {
+        let rec = HistoryRecord {
+            service: msg.0,
+            worker: msg.1,
+            event: msg.2,
+        };
+
+        self.publish(&rec);
+
+        if let Some(ref mut journal) = self.journal {
+            if let Err(err) = journal.write(&rec) {
+                error!("Can not write history file {:?}: {}", journal.path, err);
+            }
+        }
+    }
+}
+
+/// Event of service worker
+#[derive(Message)]
+pub struct WorkerEvent(pub HistoryRecord);
+
+/// Send events of services to recipient, all services and states
+/// if lists are empty
+#[derive(Message)]
+pub struct Subscribe {
+    pub services: Vec<String>,
+    pub states: Vec<String>,
+    pub rcp: Recipient<WorkerEvent>,
+}
+
+// states that can be used in subscription filter
+const STATES: [State; 11] = [
+    State::Starting,
+    State::Reloading,
+    State::Restarting,
+    State::Running,
+    State::StoppingOld,
+    State::Stopping,
+    State::Failed,
+    State::Stopped,
+    State::Paused,
+    State::RestartFailed,
+    State::ReloadFailed,
+];
+
+struct Subscriber {
+    services: Vec<String>,
+    /// all states if `None`
+    states: Option<Vec<State>>,
+    rcp: Recipient<WorkerEvent>,
+}
+
+impl Subscriber {
+    // state names are case insensitive, unknown names match nothing
+    fn new(msg: Subscribe) -> Subscriber {
+        let states = if msg.states.is_empty() {
+            None
+        } else {
+            Some(
+                STATES
+                    .iter()
+                    .filter(|state| {
+                        let name = format!("{:?}", state);
+                        msg.states.iter().any(|s| s.eq_ignore_ascii_case(&name))
+                    }).cloned()
+                    .collect(),
+            )
+        };
+        Subscriber {
+            services: msg.services,
+            states,
+            rcp: msg.rcp,
+        }
+    }
+
+    fn matches(&self, rec: &HistoryRecord) -> bool {
+        (self.services.is_empty() || self.services.contains(&rec.service))
+            && self
+                .states
+                .as_ref()
+                .map(|states| states.contains(&rec.event.state))
+                .unwrap_or(true)
+    }
+}
+
+impl Handler<Subscribe> for History {
+    type Result = ();
+
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        self.subscribers.push(Subscriber::new(msg));
+    }
+}
+
+/// Recorded events of service, optionally since unix timestamp
+/// and with specific reason only, at most `MAX_RECORDS` newest events
+pub struct Query {
+    pub service: String,
+    pub since: Option<u64>,
+    pub reason: Option<String>,
+}
+
+impl Message for Query {
+    type Result = Result<Vec<HistoryRecord>, io::Error>;
+}
+
+impl Query {
+    fn matches(&self, rec: &HistoryRecord) -> bool {
+        rec.service == self.service
+            && self.since.map(|t| rec.event.timestamp >= t).unwrap_or(true)
+            && self
+                .reason
+                .as_ref()
+                .map(|r| reason_name(rec) == r.as_str())
+                .unwrap_or(true)
+    }
+}
+
+impl Handler<Query> for History {
+    type Result = ResponseFuture<Vec<HistoryRecord>, io::Error>;
+
+    fn handle(&mut self, msg: Query, _: &mut Context<Self>) -> Self::Result {
+        let files = match self.journal {
+            Some(ref journal) => match journal.open_files() {
+                Ok(files) => files,
+                Err(err) => {
+                    error!("Can not open history file {:?}: {}", journal.path, err);
+                    return Box::new(future::err(err));
+                }
+            },
+            None => return Box::new(future::ok(Vec::new())),
+        };
+
+        // journal can be large, read it without blocking event loop
+        let (tx, rx) = oneshot::channel();
+        let res = thread::Builder::new()
+            .name("history".to_owned())
+            .spawn(move || {
+                let _ = tx.send(read(files, |rec| msg.matches(rec)));
+            });
+
+        match res {
+            Ok(_) => Box::new(rx.then(|res| match res {
+                Ok(res) => res,
+                // reader thread panicked
+                Err(_) => Err(io::Error::new(
+                    io::ErrorKind::BrokenPipe,
+                    "History reader stopped",
+                )),
+            })),
+            Err(err) => {
+                error!("Can not read history: {}", err);
+                Box::new(future::err(err))
+            }
+        }
+    }
+}
+
+// name of reason variant, i.e. `Signal` for `Signal(9)`
+fn reason_name(rec: &HistoryRecord) -> String {
+    let name = format!("{:?}", rec.event.reason);
+    match name.find('(') {
+        Some(pos) => name[..pos].to_owned(),
+        None => name,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::dev::channel::{self, AddressReceiver};
+    use event::Reason;
+
+    struct Client;
+
+    impl Actor for Client {
+        type Context = Context<Self>;
+    }
+
+    impl Handler<WorkerEvent> for Client {
+        type Result = ();
+
+        fn handle(&mut self, _: WorkerEvent, _: &mut Context<Self>) {}
+    }
+
+    fn subscribe(
+        services: &[&str], states: &[&str],
+    ) -> (Subscribe, AddressReceiver<Client>) {
+        let (tx, rx) = channel::channel::<Client>(16);
+        let msg = Subscribe {
+            services: services.iter().map(|s| s.to_string()).collect(),
+            states: states.iter().map(|s| s.to_string()).collect(),
+            rcp: Addr::new(tx).recipient(),
+        };
+        (msg, rx)
+    }
+
+    fn record(service: &str, worker: usize, state: State) -> HistoryRecord {
+        HistoryRecord {
+            service: service.to_owned(),
+            worker,
+            event: Event::new(state, Reason::ConsoleRequest, None),
+        }
+    }
+
+    fn journal(name: &str, max_size: u64) -> Journal {
+        let name = format!("fectl-history-{}-{}", name, std::process::id());
+        let path = std::env::temp_dir().join(name);
+        let journal = Journal::open(path.as_os_str(), max_size).unwrap();
+        remove(&journal);
+        Journal::open(path.as_os_str(), max_size).unwrap()
+    }
+
+    fn add(journal: &mut Journal, service: &str, worker: usize) {
+        let rec = record(service, worker, State::Running);
+        journal.write(&rec).unwrap();
+    }
+
+    fn remove(journal: &Journal) {
+        let _ = std::fs::remove_file(&journal.path);
+        let _ = std::fs::remove_file(journal.rotated());
+    }
+
+    #[test]
+    fn test_read_opened_files() {
+        let mut journal = journal("rotate", 300);
+        for worker in 0..4 {
+            add(&mut journal, "api", worker);
+        }
+
+        // rotation after files are opened does not affect reader
+        let files = journal.open_files().unwrap();
+        for worker in 4..8 {
+            add(&mut journal, "api", worker);
+        }
+        let records = read(files, |_| true).unwrap();
+        remove(&journal);
+
+        let workers: Vec<_> = records.iter().map(|rec| rec.worker).collect();
+        assert_eq!(workers, vec![0, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_read_max_records() {
+        let mut journal = journal("max", 1 << 30);
+        for worker in 0..MAX_RECORDS + 10 {
+            add(&mut journal, "api", worker);
+            add(&mut journal, "web", worker);
+        }
+        let files = journal.open_files().unwrap();
+        let records = read(files, |rec| rec.service == "api").unwrap();
+        remove(&journal);
+
+        assert_eq!(records.len(), MAX_RECORDS);
+        assert_eq!(records[0].worker, 10);
+        assert_eq!(records[MAX_RECORDS - 1].worker, MAX_RECORDS + 9);
+    }
+
+    #[test]
+    fn test_subscriber_matches() {
+        let (msg, _rx) = subscribe(&[], &[]);
+        let sub = Subscriber::new(msg);
+        assert!(sub.matches(&record("api", 1, State::Running)));
+        assert!(sub.matches(&record("web", 1, State::Failed)));
+
+        // services filter
+        let (msg, _rx) = subscribe(&["api", "db"], &[]);
+        let sub = Subscriber::new(msg);
+        assert!(sub.matches(&record("api", 1, State::Running)));
+        assert!(sub.matches(&record("db", 2, State::Stopped)));
+        assert!(!sub.matches(&record("web", 1, State::Running)));
+
+        // states filter, case insensitive
+        let (msg, _rx) = subscribe(&["api"], &["running", "FAILED", "unknown"]);
+        let sub = Subscriber::new(msg);
+        assert!(sub.matches(&record("api", 1, State::Running)));
+        assert!(sub.matches(&record("api", 1, State::Failed)));
+        assert!(!sub.matches(&record("api", 1, State::Stopped)));
+        assert!(!sub.matches(&record("web", 1, State::Running)));
+
+        // unknown states match nothing
+        let (msg, _rx) = subscribe(&[], &["unknown"]);
+        let sub = Subscriber::new(msg);
+        assert!(!sub.matches(&record("api", 1, State::Running)));
+    }
+
+    #[test]
+    fn test_subscriber_removed() {
+        let mut history = History::default();
+        let (msg, api) = subscribe(&["api"], &[]);
+        history.subscribers.push(Subscriber::new(msg));
+        let (msg, web) = subscribe(&["web"], &[]);
+        history.subscribers.push(Subscriber::new(msg));
+
+        history.publish(&record("api", 1, State::Running));
+        assert_eq!(history.subscribers.len(), 2);
+
+        // closed subscriber is removed on next matching event
+        drop(api);
+        history.publish(&record("web", 1, State::Running));
+        assert_eq!(history.subscribers.len(), 2);
+        history.publish(&record("api", 1, State::Running));
+        assert_eq!(history.subscribers.len(), 1);
+        assert_eq!(history.subscribers[0].services, vec!["web".to_owned()]);
+
+        drop(web);
+        history.publish(&record("web", 1, State::Running));
+        assert!(history.subscribers.is_empty());
+    }
+}
@@ -0,0 +1,195 @@
+// journald logging backend, native journal protocol
+use std::io::{self, Write};
+use std::os::unix::net::UnixDatagram;
+
+use byteorder::{ByteOrder, LittleEndian};
+use log::{Log, Metadata, Record};
+
+use logging;
+use syslog;
+
+/// Default journal socket
+pub const DEFAULT_ADDRESS: &str = "/run/systemd/journal/socket";
+
+/// Logger that sends records to systemd journal
+///
+/// Structured fields of the record are taken from `logging::with_fields()`
+/// scope and prefixed with `FECTL_`, i.e. `journalctl FECTL_SERVICE=api`
+/// shows records of `api` service.
+pub struct Journald {
+    name: String,
+    facility: u8,
+    target: &'static str,
+    address: String,
+    sock: UnixDatagram,
+}
+
+impl Journald {
+    /// Connect to journal socket, `name` is used as syslog identifier
+    pub fn new(
+        name: &str, facility: u8, address: &str, target: &'static str,
+    ) -> Result<Journald, io::Error> {
+        let sock = UnixDatagram::unbound()?;
+        sock.connect(address)?;
+
+        Ok(Journald {
+            name: name.to_owned(),
+            address: address.to_owned(),
+            facility,
+            target,
+            sock,
+        })
+    }
+
+    fn format(&self, record: &Record) -> Vec<u8> {
+        let mut buf = Vec::new();
+        add_field(&mut buf, "MESSAGE", &format!("{}", record.args()));
+        add_field(
+            &mut buf,
+            "PRIORITY",
+            &syslog::severity(record.level()).to_string(),
+        );
+        add_field(&mut buf, "SYSLOG_IDENTIFIER", &self.name);
+        add_field(&mut buf, "SYSLOG_FACILITY", &self.facility.to_string());
+        add_field(&mut buf, "CODE_MODULE", record.target());
+        if let Some(file) = record.file() {
+            add_field(&mut buf, "CODE_FILE", file);
+        }
+        if let Some(line) = record.line() {
+            add_field(&mut buf, "CODE_LINE", &line.to_string());
+        }
+        logging::fields(|fields| {
+            for &(ref name, ref value) in fields {
+                add_field(&mut buf, &field_name(name), value);
+            }
+        });
+        buf
+    }
+}
+
+// journal field name consists of uppercase letters, digits and underscores
+fn field_name(name: &str) -> String {
+    let name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_lowercase() {
+                c.to_ascii_uppercase()
+            } else if c.is_ascii_uppercase() || c.is_ascii_digit() {
+                c
+            } else {
+                '_'
+            }
+        }).collect();
+    format!("FECTL_{}", name)
+}
+
+// field is serialized as `NAME=value\n`, multi-line value as
+// `NAME\n`, 64bit little endian length, value and `\n`
+fn add_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_ref());
+    if value.contains('\n') {
+        let mut len = [0u8; 8];
+        LittleEndian::write_u64(&mut len, value.len() as u64);
+        buf.push(b'\n');
+        buf.extend_from_slice(&len);
+    } else {
+        buf.push(b'=');
+    }
+    buf.extend_from_slice(value.as_ref());
+    buf.push(b'\n');
+}
+
+impl Log for Journald {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with(self.target)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = self.format(record);
+
+        // journald could be restarted, socket is bound to the path
+        if self.sock.send(&msg).is_err()
+            && self
+                .sock
+                .connect(&self.address)
+                .and_then(|_| self.sock.send(&msg))
+                .is_err()
+        {
+            let _ = writeln!(
+                io::stderr(),
+                "{} {}: {}",
+                self.name,
+                record.level(),
+                record.args()
+            );
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+    use std;
+
+    #[test]
+    fn test_field_name() {
+        assert_eq!(field_name("service"), "FECTL_SERVICE");
+        assert_eq!(field_name("worker_idx"), "FECTL_WORKER_IDX");
+        assert_eq!(field_name("Pid2"), "FECTL_PID2");
+        assert_eq!(field_name("a-b.ü"), "FECTL_A_B__");
+    }
+
+    #[test]
+    fn test_log_record() {
+        let path = std::env::temp_dir()
+            .join(format!("fectl-journald-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        let journald = Journald::new("app", 3, path.to_str().unwrap(), "fectl").unwrap();
+
+        let fields = vec![("service".to_owned(), "api".to_owned())];
+        logging::with_fields(fields, || {
+            journald.log(
+                &Record::builder()
+                    .args(format_args!("line one\nline two"))
+                    .level(Level::Warn)
+                    .target("fectl::test")
+                    .line(Some(42))
+                    .build(),
+            )
+        });
+        // records of other targets are ignored
+        journald.log(
+            &Record::builder()
+                .args(format_args!("skip"))
+                .target("other")
+                .build(),
+        );
+
+        let mut buf = [0u8; 4096];
+        let size = server.recv(&mut buf).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let mut expected = b"MESSAGE\n".to_vec();
+        expected.extend_from_slice(&[17, 0, 0, 0, 0, 0, 0, 0]);
+        expected.extend_from_slice(b"line one\nline two\n");
+        expected.extend_from_slice(
+            b"PRIORITY=4\n\
+              SYSLOG_IDENTIFIER=app\n\
+              SYSLOG_FACILITY=3\n\
+              CODE_MODULE=fectl::test\n\
+              CODE_LINE=42\n\
+              FECTL_SERVICE=api\n",
+        );
+        assert_eq!(&buf[..size], &expected[..]);
+
+        server.set_nonblocking(true).unwrap();
+        assert!(server.recv(&mut buf).is_err());
+    }
+}
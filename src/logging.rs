@@ -1,14 +1,30 @@
+use std;
+use std::cell::RefCell;
 use std::io::Write;
 use std::str::FromStr;
+use std::sync::{Arc, RwLock};
 
 use env_logger::Builder;
-use log::LevelFilter;
+use log::{self, Level, LevelFilter, Log, Metadata, Record};
+use serde_json as json;
 use time;
 
 use config::LoggingConfig;
+use journald::{self, Journald};
+use syslog::{self, Syslog};
 use version::PKG_INFO;
 
-pub fn init_logging(cfg: &LoggingConfig) {
+/// Log level of worker log record, python level names are supported
+pub fn worker_level(name: &str) -> Level {
+    match name.to_lowercase().as_str() {
+        "critical" | "fatal" => Level::Error,
+        "warning" => Level::Warn,
+        name => Level::from_str(name).unwrap_or(Level::Info),
+    }
+}
+
+/// Initialize master logging, returned handle changes log level at runtime
+pub fn init_logging(cfg: &LoggingConfig) -> LogLevel {
     let level = cfg
         .level
         .as_ref()
@@ -20,17 +36,310 @@ pub fn init_logging(cfg: &LoggingConfig) {
             }
         }).unwrap_or(LevelFilter::Info);
 
-    Builder::new()
-        .format(|buf, record| {
-            let t = time::now();
-            write!(
-                buf,
-                "{},{:03} - {} - {}\n",
-                time::strftime("%Y-%m-%d %H:%M:%S", &t).unwrap(),
-                t.tm_nsec / 1000_000,
-                record.level(),
-                record.args()
+    let logger = match cfg.service.as_str() {
+        "console" => None,
+        "syslog" | "journald" => init_remote(cfg),
+        service => {
+            println!("Unknown logging service {:?}, using `console`", service);
+            None
+        }
+    };
+    let logger = logger.unwrap_or_else(|| init_console(cfg));
+
+    let log_level = LogLevel::new(level);
+    log::set_max_level(level);
+    let logger = Logger {
+        level: log_level.clone(),
+        inner: logger,
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_err() {
+        println!("Logger is already initialized");
+    }
+    log_level
+}
+
+fn init_console(cfg: &LoggingConfig) -> Box<Log> {
+    let json = match cfg.format.as_ref().map(|s| s.as_str()) {
+        None | Some("text") => false,
+        Some("json") => true,
+        Some(format) => {
+            println!("Unknown logging format {:?}, using `text`", format);
+            false
+        }
+    };
+
+    Box::new(
+        Builder::new()
+            .format(move |buf, record| {
+                if json {
+                    return writeln!(buf, "{}", format_json(record));
+                }
+                let t = time::now();
+                write!(
+                    buf,
+                    "{},{:03} - {} - {}\n",
+                    time::strftime("%Y-%m-%d %H:%M:%S", &t).unwrap(),
+                    t.tm_nsec / 1000_000,
+                    record.level(),
+                    record.args()
+                )
+            }).filter(Some(PKG_INFO.name), LevelFilter::Trace)
+            .build(),
+    )
+}
+
+/// Keys of json record, structured fields with same name get `field_` prefix
+const RESERVED_KEYS: [&str; 4] = ["timestamp", "level", "target", "message"];
+
+// one json object per record, structured fields are added as object keys
+fn format_json(record: &Record) -> json::Value {
+    let t = time::now_utc();
+    let mut obj = json::Map::new();
+    obj.insert(
+        "timestamp".to_owned(),
+        json::Value::String(format!(
+            "{}.{:03}Z",
+            time::strftime("%Y-%m-%dT%H:%M:%S", &t).unwrap(),
+            t.tm_nsec / 1_000_000
+        )),
+    );
+    obj.insert(
+        "level".to_owned(),
+        json::Value::String(record.level().to_string()),
+    );
+    obj.insert(
+        "target".to_owned(),
+        json::Value::String(record.target().to_owned()),
+    );
+    obj.insert(
+        "message".to_owned(),
+        json::Value::String(format!("{}", record.args())),
+    );
+    fields(|fields| {
+        for &(ref name, ref value) in fields {
+            // fields could not override keys of the record
+            let name = if RESERVED_KEYS.contains(&name.as_str()) {
+                format!("field_{}", name)
+            } else {
+                name.clone()
+            };
+            obj.insert(name, json::Value::String(value.clone()));
+        }
+    });
+    json::Value::Object(obj)
+}
+
+// syslog or journald backend, returns `None` if server is not available
+fn init_remote(cfg: &LoggingConfig) -> Option<Box<Log>> {
+    let facility = match cfg.facility {
+        Some(ref name) => match syslog::facility(name) {
+            Some(facility) => facility,
+            None => {
+                println!("Unknown syslog facility {:?}, using `user`", name);
+                1
+            }
+        },
+        None => 1,
+    };
+    let journald = cfg.service == "journald";
+    let address = cfg
+        .address
+        .as_ref()
+        .map(|s| s.as_str())
+        .unwrap_or(if journald {
+            journald::DEFAULT_ADDRESS
+        } else {
+            syslog::DEFAULT_ADDRESS
+        });
+
+    let logger = if journald {
+        Journald::new(&cfg.name, facility, address, PKG_INFO.name)
+            .map(|logger| Box::new(logger) as Box<Log>)
+    } else {
+        Syslog::new(&cfg.name, facility, address, PKG_INFO.name)
+            .map(|logger| Box::new(logger) as Box<Log>)
+    };
+    match logger {
+        Ok(logger) => Some(logger),
+        Err(err) => {
+            println!(
+                "Can not connect to {} {:?}: {}, using `console`",
+                cfg.service, address, err
+            );
+            None
+        }
+    }
+}
+
+struct Levels {
+    configured: LevelFilter,
+    level: LevelFilter,
+    modules: Vec<(String, LevelFilter)>,
+    generation: u64,
+}
+
+/// Log level of master process, could be changed at runtime
+/// for all records or for records of specific module
+#[derive(Clone)]
+pub struct LogLevel(Arc<RwLock<Levels>>);
+
+impl LogLevel {
+    fn new(level: LevelFilter) -> LogLevel {
+        LogLevel(Arc::new(RwLock::new(Levels {
+            configured: level,
+            level,
+            modules: Vec::new(),
+            generation: 0,
+        })))
+    }
+
+    /// Set log level, module is a prefix of record target,
+    /// i.e. `worker` or `fectld::worker`. Returns generation of the change,
+    /// it is used for reverting this change
+    pub fn set(&self, level: LevelFilter, module: Option<String>) -> u64 {
+        let mut levels = self.0.write().unwrap();
+        match module {
+            Some(module) => {
+                let module = if module.contains("::") {
+                    module
+                } else {
+                    // crate name of master binary
+                    let krate = module_path!().split("::").next().unwrap();
+                    format!("{}::{}", krate, module)
+                };
+                levels.modules.retain(|m| m.0 != module);
+                levels.modules.push((module, level));
+            }
+            None => {
+                levels.level = level;
+                levels.modules.clear();
+            }
+        }
+        levels.generation += 1;
+        levels.update_max_level();
+        levels.generation
+    }
+
+    /// Restore configured log level, unless level is changed again
+    /// after change with `generation`
+    pub fn revert(&self, generation: Option<u64>) {
+        let level = {
+            let mut levels = self.0.write().unwrap();
+            if !generation
+                .map(|gen| gen == levels.generation)
+                .unwrap_or(true)
+            {
+                return;
+            }
+            levels.level = levels.configured;
+            levels.modules.clear();
+            levels.generation += 1;
+            levels.update_max_level();
+            levels.level
+        };
+        info!("Log level is restored to {}", level);
+    }
+
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        let levels = self.0.read().unwrap();
+        let target = metadata.target();
+        let level = levels
+            .modules
+            .iter()
+            .filter(|&&(ref module, _)| {
+                target == module
+                    || (target.starts_with(module.as_str())
+                        && target[module.len()..].starts_with("::"))
+            }).max_by_key(|&&(ref module, _)| module.len())
+            .map(|&(_, level)| level)
+            .unwrap_or(levels.level);
+        metadata.level() <= level
+    }
+}
+
+impl Levels {
+    fn update_max_level(&self) {
+        let max = self
+            .modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.level, std::cmp::max);
+        log::set_max_level(max);
+    }
+}
+
+// filters records of backend logger according to current log level
+struct Logger {
+    level: LogLevel,
+    inner: Box<Log>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.level.enabled(metadata) && self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if self.level.enabled(record.metadata()) {
+            self.inner.log(record)
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+thread_local! {
+    static FIELDS: RefCell<Vec<(String, String)>> = RefCell::new(Vec::new());
+}
+
+/// Attach structured fields to log records emitted by `f`,
+/// fields are used by journald backend and json format
+pub fn with_fields<F, R>(fields: Vec<(String, String)>, f: F) -> R
+where
+    F: FnOnce() -> R,
+{
+    let prev = FIELDS.with(|cur| std::mem::replace(&mut *cur.borrow_mut(), fields));
+    let res = f();
+    FIELDS.with(|cur| *cur.borrow_mut() = prev);
+    res
+}
+
+/// Structured fields of current scope
+pub fn fields<F>(f: F)
+where
+    F: FnOnce(&[(String, String)]),
+{
+    FIELDS.with(|cur| f(&cur.borrow()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_json_reserved_fields() {
+        let fields = vec![
+            ("message".to_owned(), "spoofed".to_owned()),
+            ("level".to_owned(), "ERROR".to_owned()),
+            ("service".to_owned(), "api".to_owned()),
+        ];
+        let record = with_fields(fields, || {
+            format_json(
+                &Record::builder()
+                    .args(format_args!("original"))
+                    .level(Level::Info)
+                    .target("fectl")
+                    .build(),
             )
-        }).filter(Some(PKG_INFO.name), level)
-        .init();
+        });
+
+        assert_eq!(record["message"], "original");
+        assert_eq!(record["level"], "INFO");
+        assert_eq!(record["target"], "fectl");
+        assert_eq!(record["field_message"], "spoofed");
+        assert_eq!(record["field_level"], "ERROR");
+        assert_eq!(record["service"], "api");
+    }
 }
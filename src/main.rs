@@ -19,6 +19,7 @@ extern crate mio;
 extern crate net2;
 extern crate nix;
 extern crate tokio;
+extern crate tokio_signal;
 extern crate toml;
 
 extern crate actix;
@@ -30,13 +31,22 @@ mod config;
 mod config_helpers;
 mod event;
 mod exec;
+mod history;
 mod io;
+mod journald;
 mod logging;
 mod master;
 mod master_types;
+mod notify;
+mod output;
+mod persist;
 mod process;
+mod reaper;
+mod restart;
 mod service;
 mod socket;
+mod syslog;
+mod upgrade;
 mod utils;
 mod worker;
 
@@ -1,35 +1,44 @@
 use std;
 use std::ffi::OsStr;
 use std::io;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd};
 use std::os::unix::net::UnixListener as StdUnixListener;
 use std::rc::Rc;
-use std::thread;
-use std::time::Duration;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
-use futures::Stream;
+use futures::{Future, Stream};
 use libc;
+use log::LevelFilter;
 use nix;
 use serde_json as json;
 use tokio::codec::{Decoder, Encoder, FramedRead};
 use tokio::io::{WriteHalf, AsyncRead};
 use tokio::net::{UnixListener, UnixStream};
 use tokio::reactor::Handle;
+use tokio::timer::Delay;
 
 use client;
 use cmd::{self, CommandCenter, CommandError};
-use config::Config;
-use logging;
+use config::{Config, MasterConfig};
+use history::{self, History, WorkerEvent};
+use logging::{self, LogLevel};
 use master_types::{MasterRequest, MasterResponse};
+use output::FollowLine;
+use reaper;
 use service::{ReloadStatus, ServiceOperationError, StartStatus};
+use upgrade::{self, UpgradeState};
 use version::PKG_INFO;
 
 pub struct Master {
     cfg: Rc<Config>,
     cmd: Addr<CommandCenter>,
+    log_level: LogLevel,
+    // lock is held while master process is alive
+    _lock: std::fs::File,
 }
 
 impl Actor for Master {
@@ -42,6 +51,7 @@ struct NetStream(UnixStream);
 impl StreamHandler<NetStream, io::Error> for Master {
     fn handle(&mut self, msg: NetStream, _: &mut Context<Self>) {
         let cmd = self.cmd.clone();
+        let log_level = self.log_level.clone();
 
         MasterClient::create(|ctx| {
             let (r, w) = msg.0.split();
@@ -49,6 +59,7 @@ impl StreamHandler<NetStream, io::Error> for Master {
 
             MasterClient {
                 cmd,
+                log_level,
                 framed: actix::io::FramedWrite::new(w, MasterTransportCodec, ctx),
             }
         });
@@ -63,6 +74,7 @@ impl Drop for Master {
 
 struct MasterClient {
     cmd: Addr<CommandCenter>,
+    log_level: LogLevel,
     framed: actix::io::FramedWrite<WriteHalf<UnixStream>, MasterTransportCodec>,
 }
 
@@ -76,6 +88,22 @@ impl Actor for MasterClient {
 
 impl actix::io::WriteHandler<io::Error> for MasterClient {}
 
+impl Handler<FollowLine> for MasterClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: FollowLine, _: &mut Context<Self>) {
+        self.framed.write(MasterResponse::ServiceLog(msg.0));
+    }
+}
+
+impl Handler<WorkerEvent> for MasterClient {
+    type Result = ();
+
+    fn handle(&mut self, msg: WorkerEvent, _: &mut Context<Self>) {
+        self.framed.write(MasterResponse::Event(msg.0));
+    }
+}
+
 impl StreamHandler<MasterRequest, io::Error> for MasterClient {
     fn handle(&mut self, msg: MasterRequest, ctx: &mut Self::Context) {
         ctx.notify(msg);
@@ -117,7 +145,16 @@ impl MasterClient {
                 ServiceOperationError::Failed => {
                     self.framed.write(MasterResponse::ErrorServiceFailed)
                 }
+                ServiceOperationError::UnknownRelease => {
+                    self.framed.write(MasterResponse::ErrorUnknownRelease)
+                }
+                ServiceOperationError::ScaleOutOfRange(min, max) => {
+                    self.framed.write(MasterResponse::ErrorScaleOutOfRange(min, max))
+                }
             },
+            CommandError::Upgrade(err) => {
+                self.framed.write(MasterResponse::ErrorUpgrade(err))
+            }
         };
     }
 
@@ -142,34 +179,61 @@ impl MasterClient {
             }).spawn(ctx);
     }
 
-    fn reload(&mut self, name: String, ctx: &mut Context<Self>, graceful: bool) {
+    fn reload(
+        &mut self, name: String, ctx: &mut Context<Self>, graceful: bool, canary: bool,
+    ) {
         info!("Client command: Reload service '{}'", name);
 
         self.cmd
-            .send(cmd::ReloadService(name, graceful))
+            .send(cmd::ReloadService(name, graceful, canary))
             .into_actor(self)
             .then(|res, srv, ctx| {
                 match res {
                     Err(_) => (),
                     Ok(Err(err)) => srv.handle_error(err, ctx),
-                    Ok(Ok(res)) => {
-                        match res {
-                            ReloadStatus::Success => {
-                                srv.framed.write(MasterResponse::ServiceStarted)
-                            }
-                            ReloadStatus::Failed => {
-                                srv.framed.write(MasterResponse::ServiceFailed)
-                            }
-                            ReloadStatus::Stopping => {
-                                srv.framed.write(MasterResponse::ErrorServiceStopping)
-                            }
-                        };
-                    }
+                    Ok(Ok(res)) => srv.reload_status(res),
                 }
                 actix::fut::ok(())
             }).spawn(ctx);
     }
 
+    fn deploy(
+        &mut self, name: String, release: Option<String>, canary: bool,
+        ctx: &mut Context<Self>,
+    ) {
+        match release {
+            Some(ref release) => {
+                info!("Client command: Deploy release '{}' of '{}'", release, name)
+            }
+            None => info!("Client command: Rollback service '{}'", name),
+        }
+
+        self.cmd
+            .send(cmd::DeployService(name, release, canary))
+            .into_actor(self)
+            .then(|res, srv, ctx| {
+                match res {
+                    Err(_) => (),
+                    Ok(Err(err)) => srv.handle_error(err, ctx),
+                    Ok(Ok(res)) => srv.reload_status(res),
+                }
+                actix::fut::ok(())
+            }).spawn(ctx);
+    }
+
+    fn reload_status(&mut self, status: ReloadStatus) {
+        match status {
+            ReloadStatus::Success => self.framed.write(MasterResponse::ServiceStarted),
+            ReloadStatus::Failed => self.framed.write(MasterResponse::ServiceFailed),
+            ReloadStatus::Stopping => {
+                self.framed.write(MasterResponse::ErrorServiceStopping)
+            }
+            ReloadStatus::CanaryFailed => {
+                self.framed.write(MasterResponse::ServiceCanaryFailed)
+            }
+        };
+    }
+
     fn start_service(&mut self, name: String, ctx: &mut Context<Self>) {
         info!("Client command: Start service '{}'", name);
 
@@ -197,6 +261,42 @@ impl MasterClient {
                 actix::fut::ok(())
             }).spawn(ctx);
     }
+
+    fn set_log_level(
+        &mut self, level: &str, module: Option<String>, timeout: Option<u64>,
+    ) {
+        if level == "reset" {
+            info!("Client command: Reset log level");
+            self.log_level.revert(None);
+            self.framed.write(MasterResponse::Done);
+            return;
+        }
+        let level = match LevelFilter::from_str(level) {
+            Ok(level) => level,
+            Err(_) => {
+                self.framed.write(MasterResponse::ErrorUnknownLogLevel);
+                return;
+            }
+        };
+        match module {
+            Some(ref module) => {
+                info!("Client command: Set log level of '{}' to {}", module, level)
+            }
+            None => info!("Client command: Set log level to {}", level),
+        }
+
+        let generation = self.log_level.set(level, module);
+        if let Some(timeout) = timeout {
+            // master client could be closed before timeout
+            let log_level = self.log_level.clone();
+            let delay = Delay::new(Instant::now() + Duration::new(timeout, 0));
+            Arbiter::spawn(delay.then(move |_| {
+                log_level.revert(Some(generation));
+                Ok(())
+            }));
+        }
+        self.framed.write(MasterResponse::Done);
+    }
 }
 
 impl Message for MasterRequest {
@@ -212,9 +312,30 @@ impl Handler<MasterRequest> for MasterClient {
                 self.framed.write(MasterResponse::Pong);
             }
             MasterRequest::Start(name) => self.start_service(name, ctx),
-            MasterRequest::Reload(name) => self.reload(name, ctx, true),
-            MasterRequest::Restart(name) => self.reload(name, ctx, false),
+            MasterRequest::Reload(name) => self.reload(name, ctx, true, false),
+            MasterRequest::CanaryReload(name) => self.reload(name, ctx, true, true),
+            MasterRequest::Restart(name) => self.reload(name, ctx, false, false),
+            MasterRequest::Deploy(name, release, canary) => {
+                self.deploy(name, Some(release), canary, ctx)
+            }
+            MasterRequest::Rollback(name) => self.deploy(name, None, false, ctx),
             MasterRequest::Stop(name) => self.stop(name, ctx),
+            MasterRequest::Scale(name, num, persist) => {
+                info!("Client command: Scale service '{}' to {}", name, num);
+                self.cmd
+                    .send(cmd::ScaleService(name, num, persist))
+                    .into_actor(self)
+                    .then(|res, srv, ctx| {
+                        match res {
+                            Err(_) => (),
+                            Ok(Err(err)) => srv.handle_error(err, ctx),
+                            Ok(Ok(_)) => {
+                                srv.framed.write(MasterResponse::Done);
+                            }
+                        };
+                        actix::fut::ok(())
+                    }).spawn(ctx);
+            }
             MasterRequest::Pause(name) => {
                 info!("Client command: Pause service '{}'", name);
                 self.cmd
@@ -280,6 +401,93 @@ impl Handler<MasterRequest> for MasterClient {
                         actix::fut::ok(())
                     }).spawn(ctx);
             }
+            MasterRequest::Logs {
+                service,
+                worker,
+                lines,
+                follow,
+            } => {
+                debug!("Client command: Service logs '{}'", service);
+                let follow = if follow {
+                    Some(ctx.address().recipient())
+                } else {
+                    None
+                };
+                let stop = follow.is_none();
+                self.cmd
+                    .send(cmd::ServiceLogs {
+                        name: service,
+                        worker,
+                        lines,
+                        follow,
+                    }).into_actor(self)
+                    .then(move |res, srv, ctx| {
+                        match res {
+                            Err(_) => (),
+                            Ok(Err(err)) => srv.handle_error(err, ctx),
+                            Ok(Ok(lines)) => {
+                                for line in lines {
+                                    srv.framed.write(MasterResponse::ServiceLog(line));
+                                }
+                                if stop {
+                                    srv.framed.write(MasterResponse::Done);
+                                }
+                            }
+                        };
+                        actix::fut::ok(())
+                    }).spawn(ctx);
+            }
+            MasterRequest::History {
+                service,
+                since,
+                reason,
+            } => {
+                debug!("Client command: Service history '{}'", service);
+                System::current()
+                    .registry()
+                    .get::<History>()
+                    .send(history::Query {
+                        service,
+                        since,
+                        reason,
+                    }).into_actor(self)
+                    .then(|res, srv, _| {
+                        match res {
+                            Ok(Ok(records)) => {
+                                for rec in records {
+                                    srv.framed.write(MasterResponse::HistoryEvent(rec));
+                                }
+                                srv.framed.write(MasterResponse::Done);
+                            }
+                            Ok(Err(err)) => srv
+                                .framed
+                                .write(MasterResponse::ErrorHistory(format!("{}", err))),
+                            Err(_) => srv.framed.write(MasterResponse::ErrorHistory(
+                                "History service is not available".to_owned(),
+                            )),
+                        }
+                        actix::fut::ok(())
+                    }).spawn(ctx);
+            }
+            MasterRequest::Subscribe { services, states } => {
+                debug!("Client command: Subscribe to events of {:?}", services);
+                System::current()
+                    .registry()
+                    .get::<History>()
+                    .do_send(history::Subscribe {
+                        services,
+                        states,
+                        rcp: ctx.address().recipient(),
+                    });
+            }
+            MasterRequest::ReopenLogs => {
+                info!("Client command: Reopen log files");
+                self.cmd.do_send(cmd::ReopenLogs);
+                self.framed.write(MasterResponse::Done);
+            }
+            MasterRequest::SetLogLevel(level, module, timeout) => {
+                self.set_log_level(&level, module, timeout)
+            }
             MasterRequest::Pid => {
                 self.framed
                     .write(MasterResponse::Pid(format!("{}", nix::unistd::getpid())));
@@ -290,6 +498,22 @@ impl Handler<MasterRequest> for MasterClient {
                     PKG_INFO.name, PKG_INFO.version
                 )));
             }
+            MasterRequest::Upgrade => {
+                info!("Client command: Upgrade master process");
+                self.cmd
+                    .send(cmd::Upgrade)
+                    .into_actor(self)
+                    .then(|res, srv, ctx| {
+                        match res {
+                            Err(_) => (),
+                            Ok(Err(err)) => srv.handle_error(err, ctx),
+                            Ok(Ok(_)) => {
+                                srv.framed.write(MasterResponse::Done);
+                            }
+                        };
+                        actix::fut::ok(())
+                    }).spawn(ctx);
+            }
             MasterRequest::Quit => {
                 self.cmd
                     .send(cmd::Stop)
@@ -337,6 +561,12 @@ impl Encoder for MasterTransportCodec {
     ) -> Result<(), Self::Error> {
         let msg = json::to_string(&msg).unwrap();
         let msg_ref: &[u8] = msg.as_ref();
+        if msg_ref.len() > usize::from(u16::MAX) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("Response is too large: {} bytes", msg_ref.len()),
+            ));
+        }
 
         dst.reserve(msg_ref.len() + 2);
         dst.put_u16_be(msg_ref.len() as u16);
@@ -346,58 +576,23 @@ impl Encoder for MasterTransportCodec {
     }
 }
 
-const HOST: &str = "127.0.0.1:57897";
-
-/// Start master process
-pub fn start(cfg: Config) -> bool {
-    // init logging
-    logging::init_logging(&cfg.logging);
-
-    info!("Starting fectl process");
-
-    // change working dir
-    if let Err(err) = nix::unistd::chdir::<OsStr>(cfg.master.directory.as_ref()) {
-        error!(
-            "Can not change directory {:?} err: {}",
-            cfg.master.directory, err
-        );
-        return false;
-    }
-
-    // check if other app is running
-    for idx in 0..10 {
-        match std::net::TcpListener::bind(HOST) {
-            Ok(listener) => {
-                std::mem::forget(listener);
-                break;
-            }
-            Err(_) => {
-                if idx == 8 {
-                    error!("Can not start: Another process is running.");
-                    return false;
-                }
-                info!("Trying to bind address, sleep for 5 seconds");
-                thread::sleep(Duration::new(5, 0));
-            }
-        }
-    }
-
-    // create commands listener and also check if service process is running
-    let lst: StdUnixListener = match StdUnixListener::bind(&cfg.master.sock) {
-        Ok(lst) => lst,
+// create commands listener and also check if service process is running
+fn listen(cfg: &Config) -> Option<StdUnixListener> {
+    match StdUnixListener::bind(&cfg.master.sock) {
+        Ok(lst) => Some(lst),
         Err(err) => match err.kind() {
             io::ErrorKind::PermissionDenied => {
                 error!(
                     "Can not create socket file {:?} err: Permission denied.",
                     cfg.master.sock
                 );
-                return false;
+                None
             }
             io::ErrorKind::AddrInUse => {
                 match client::is_alive(&cfg.master) {
                     client::AliveStatus::Alive => {
                         error!("Can not start: Another process is running.");
-                        return false;
+                        None
                     }
                     client::AliveStatus::NotResponding => {
                         error!("Master process is not responding.");
@@ -406,16 +601,16 @@ pub fn start(cfg: Config) -> bool {
                         } else {
                             error!("Can not load pid of the master process.");
                         }
-                        return false;
+                        None
                     }
                     client::AliveStatus::NotAlive => {
                         // remove socket and try again
                         let _ = std::fs::remove_file(&cfg.master.sock);
                         match StdUnixListener::bind(&cfg.master.sock) {
-                            Ok(lst) => lst,
+                            Ok(lst) => Some(lst),
                             Err(err) => {
                                 error!("Can not create listener socket: {}", err);
-                                return false;
+                                None
                             }
                         }
                     }
@@ -423,10 +618,102 @@ pub fn start(cfg: Config) -> bool {
             }
             _ => {
                 error!("Can not create listener socket: {}", err);
-                return false;
+                None
             }
         },
+    }
+}
+
+/// Redirect stdout and stderr of daemonized master process,
+/// also used for reopening files after rotation
+pub fn redirect_output(cfg: &MasterConfig) {
+    if let Some(ref stdout) = cfg.stdout {
+        match std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(stdout)
+        {
+            Ok(f) => {
+                let _ = nix::unistd::dup2(f.as_raw_fd(), libc::STDOUT_FILENO);
+            }
+            Err(err) => error!("Can open stdout file {}: {}", stdout, err),
+        }
+    }
+    if let Some(ref stderr) = cfg.stderr {
+        match std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(stderr)
+        {
+            Ok(f) => {
+                let _ = nix::unistd::dup2(f.as_raw_fd(), libc::STDERR_FILENO);
+            }
+            Err(err) => error!("Can open stderr file {}: {}", stderr, err),
+        }
+    }
+}
+
+/// Start master process
+pub fn start(cfg: Config) -> bool {
+    // init logging
+    let log_level = logging::init_logging(&cfg.logging);
+
+    info!("Starting fectl process");
+
+    // change working dir
+    if let Err(err) = nix::unistd::chdir::<OsStr>(cfg.master.directory.as_ref()) {
+        error!(
+            "Can not change directory {:?} err: {}",
+            cfg.master.directory, err
+        );
+        return false;
+    }
+
+    // state of previous master process, commands listener and lock are inherited
+    let upgrade = UpgradeState::load();
+
+    // inherited descriptors must not leak into new workers
+    if let Some(ref state) = upgrade {
+        for fd in state.fds() {
+            upgrade::set_cloexec(fd, true);
+        }
+    }
+
+    // check if other app is running
+    let lock = match upgrade.as_ref().and_then(|state| state.lock) {
+        Some(fd) => Ok(unsafe { std::fs::File::from_raw_fd(fd) }),
+        None => cfg.master.lock(),
     };
+    let lock = match lock {
+        Ok(lock) => lock,
+        Err(err) => {
+            if err.kind() == io::ErrorKind::WouldBlock {
+                if let Some(pid) = cfg.master.load_lock_pid() {
+                    error!("Can not start: Another process is running (pid:{})", pid);
+                } else {
+                    error!("Can not start: Another process is running.");
+                }
+            } else {
+                error!("Can not lock file {:?} err: {}", cfg.master.lock, err);
+            }
+            return false;
+        }
+    };
+
+    // workers are tagged with lock path, lock identifies master process
+    let id = std::fs::canonicalize(&cfg.master.lock)
+        .map(|path| path.into_os_string())
+        .unwrap_or_else(|_| cfg.master.lock.clone());
+    std::env::set_var(reaper::MASTER_VAR, id);
+
+    let lst = match upgrade {
+        Some(ref state) => unsafe { StdUnixListener::from_raw_fd(state.sock) },
+        None => match listen(&cfg) {
+            Some(lst) => lst,
+            None => return false,
+        },
+    };
+    let sock = lst.as_raw_fd();
 
     // try to save pid
     if let Err(err) = cfg.master.save_pid() {
@@ -450,8 +737,9 @@ pub fn start(cfg: Config) -> bool {
         }
     }
 
+    // upgraded master process is already daemonized
     let daemon = cfg.master.daemon;
-    if daemon {
+    if daemon && upgrade.is_none() {
         if let Err(err) = nix::unistd::daemon(true, false) {
             error!("Can not daemonize process: {}", err);
             return false;
@@ -461,37 +749,23 @@ pub fn start(cfg: Config) -> bool {
         let _ = nix::unistd::close(libc::STDIN_FILENO);
 
         // redirect stdout and stderr
-        if let Some(ref stdout) = cfg.master.stdout {
-            match std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(stdout)
-                {
-                    Ok(f) => {
-                        let _ = nix::unistd::dup2(f.as_raw_fd(), libc::STDOUT_FILENO);
-                    }
-                    Err(err) => error!("Can open stdout file {}: {}", stdout, err),
-                }
-        }
-        if let Some(ref stderr) = cfg.master.stderr {
-            match std::fs::OpenOptions::new()
-                .append(true)
-                .create(true)
-                .open(stderr)
-                {
-                    Ok(f) => {
-                        let _ = nix::unistd::dup2(f.as_raw_fd(), libc::STDERR_FILENO);
-                    }
-                    Err(err) => error!("Can open stderr file {}: {}", stderr, err),
-                }
-        }
+        redirect_output(&cfg.master);
 
         // continue start process
         nix::sys::stat::umask(nix::sys::stat::Mode::from_bits(0o22).unwrap());
     }
 
+    // pid of the lock holder
+    if let Err(err) = cfg.master.save_lock_pid(&lock) {
+        error!("Can not write lock file {:?} err: {}", cfg.master.lock, err);
+        return false;
+    }
+
     let cfg = Rc::new(cfg);
 
+    // orphaned worker subprocesses get reparented to master
+    reaper::set_subreaper();
+
     // create uds stream
     let lst = match UnixListener::from_std(lst, &Handle::default()) {
         Ok(lst) => lst,
@@ -502,12 +776,17 @@ pub fn start(cfg: Config) -> bool {
     };
 
     // command center
-    let cmd = CommandCenter::start(cfg.clone());
+    let cmd = CommandCenter::start(cfg.clone(), sock, lock.as_raw_fd(), upgrade);
 
     // start uds master server
     let _ = Master::create(|ctx| {
         ctx.add_stream(lst.incoming().map(|s| NetStream(s)));
-        Master { cfg, cmd }
+        Master {
+            cfg,
+            cmd,
+            log_level,
+            _lock: lock,
+        }
     });
 
     if !daemon {
@@ -515,3 +794,34 @@ pub fn start(cfg: Config) -> bool {
     }
     true
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use master_types::LogLine;
+
+    fn log_line(line: String) -> MasterResponse {
+        MasterResponse::ServiceLog(LogLine {
+            worker: 1,
+            pid: "1".to_owned(),
+            stream: "stdout".to_owned(),
+            line,
+        })
+    }
+
+    #[test]
+    fn test_encode_frame_size() {
+        let mut dst = BytesMut::new();
+        MasterTransportCodec
+            .encode(log_line("line".to_owned()), &mut dst)
+            .unwrap();
+        assert_eq!(BigEndian::read_u16(dst.as_ref()) as usize, dst.len() - 2);
+
+        // escaped control characters do not fit into frame
+        let mut dst = BytesMut::new();
+        let line = String::from_utf8(vec![1; 20_000]).unwrap();
+        let res = MasterTransportCodec.encode(log_line(line), &mut dst);
+        assert!(res.is_err());
+        assert!(dst.is_empty());
+    }
+}
@@ -1,4 +1,4 @@
-use event::ServiceStatus;
+use event::{Event, ServiceStatus};
 
 /// Master command
 #[allow(non_camel_case_types)]
@@ -11,6 +11,14 @@ pub enum MasterRequest {
     Status(String),
     /// Service pids
     SPid(String),
+    /// Captured output of service workers, last `lines` buffered lines
+    /// of all workers or of specific worker, live lines if `follow` is set
+    Logs {
+        service: String,
+        worker: Option<usize>,
+        lines: Option<usize>,
+        follow: bool,
+    },
     /// Start service
     Start(String),
     /// Pause service
@@ -19,12 +27,41 @@ pub enum MasterRequest {
     Resume(String),
     /// Gracefully reload service
     Reload(String),
+    /// Gracefully reload service, reload one worker first
+    CanaryReload(String),
     /// Restart service
     Restart(String),
+    /// Deploy service release, reload one worker first if flag is set
+    Deploy(String, String, bool),
+    /// Roll back service to previous release
+    Rollback(String),
+    /// Change number of workers, optionally persist across master restarts
+    Scale(String, u16, bool),
     /// Gracefully stop service
     Stop(String),
     /// Pid of the master process
     Pid,
+    /// Execute new master binary, running workers are adopted
+    Upgrade,
+    /// Recorded events of service, optionally since unix timestamp
+    /// and with specific reason only
+    History {
+        service: String,
+        since: Option<u64>,
+        reason: Option<String>,
+    },
+    /// Stream events of services workers, all services and states
+    /// if lists are empty
+    Subscribe {
+        services: Vec<String>,
+        states: Vec<String>,
+    },
+    /// Reopen log files of master and workers
+    ReopenLogs,
+    /// Set log level of master process, optionally for specific module only,
+    /// level is restored after timeout in seconds. `reset` level restores
+    /// configured level
+    SetLogLevel(String, Option<String>, Option<u64>),
     /// Quit process
     Quit,
     /// Version if the master
@@ -49,10 +86,19 @@ pub enum MasterResponse {
     ServiceStopped,
     /// Service failed, service is not available
     ServiceFailed,
+    /// Canary worker failed, reload is stopped
+    ServiceCanaryFailed,
     /// Service status
     ServiceStatus(ServiceStatus),
     /// Service workers pids
     ServiceWorkerPids(Vec<String>),
+    /// Line of captured output, `Done` is sent after buffered lines
+    /// unless output is followed
+    ServiceLog(LogLine),
+    /// Recorded event of service worker, `Done` is sent after last event
+    HistoryEvent(HistoryRecord),
+    /// Event of service worker, sent to subscribed client
+    Event(HistoryRecord),
 
     /// System not ready
     ErrorNotReady,
@@ -70,4 +116,34 @@ pub enum MasterResponse {
     ErrorServiceStopped,
     /// Service is failed
     ErrorServiceFailed,
+    /// Release is not configured, or there is no previous release
+    ErrorUnknownRelease,
+    /// Number of workers is out of `min_workers` and `max_workers` limits
+    ErrorScaleOutOfRange(u16, u16),
+    /// Master binary upgrade failed
+    ErrorUpgrade(String),
+    /// Log level can not be parsed
+    ErrorUnknownLogLevel,
+    /// History journal can not be read
+    ErrorHistory(String),
+}
+
+/// Line of captured worker output
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct LogLine {
+    /// Worker number, starts from 1
+    pub worker: usize,
+    pub pid: String,
+    /// `stdout` or `stderr`
+    pub stream: String,
+    pub line: String,
+}
+
+/// Worker event stored in history journal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct HistoryRecord {
+    pub service: String,
+    /// Worker number, starts from 1
+    pub worker: usize,
+    pub event: Event,
 }
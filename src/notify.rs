@@ -0,0 +1,97 @@
+// systemd service manager notifications, see sd_notify(3)
+use std;
+use std::ffi::OsString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::time::Duration;
+
+use libc;
+use nix::unistd::getpid;
+
+/// Environment variables set by service manager, workers do not inherit them
+pub const ENV_VARS: &[&str] = &["NOTIFY_SOCKET", "WATCHDOG_USEC", "WATCHDOG_PID"];
+
+/// Notification socket of service manager
+fn socket_path() -> Option<OsString> {
+    std::env::var_os("NOTIFY_SOCKET").filter(|path| !path.is_empty())
+}
+
+/// Check if master process is started by service manager with `Type=notify`
+pub fn enabled() -> bool {
+    socket_path().is_some()
+}
+
+/// Send state to service manager, does nothing if `NOTIFY_SOCKET` is not set
+pub fn notify(state: &str) {
+    let path = match socket_path() {
+        Some(path) => path,
+        None => return,
+    };
+    if let Err(err) = send(path.as_bytes(), state.as_bytes()) {
+        warn!("Can not send notification {:?}: {}", state, err);
+    }
+}
+
+// send datagram to unix socket, socket in abstract namespace starts with `@`
+fn send(path: &[u8], msg: &[u8]) -> Result<(), io::Error> {
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if path.len() >= addr.sun_path.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "notify socket path is too long",
+        ));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(path.iter()) {
+        *dst = *src as libc::c_char;
+    }
+    let mut len = mem::size_of::<libc::sa_family_t>() + path.len();
+    if path[0] == b'@' {
+        addr.sun_path[0] = 0;
+    } else {
+        len += 1;
+    }
+
+    let fd =
+        unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let res = unsafe {
+        libc::sendto(
+            fd,
+            msg.as_ptr() as *const libc::c_void,
+            msg.len(),
+            0,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if res < 0 {
+        Err(err)
+    } else {
+        Ok(())
+    }
+}
+
+/// Service is started, master pid could change after daemonizing
+pub fn ready() {
+    notify(&format!("READY=1\nMAINPID={}", getpid()));
+}
+
+/// Interval of watchdog keep-alive pings, half of the configured timeout
+pub fn watchdog() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.parse::<i32>().ok() != Some(getpid().as_raw()) {
+            return None;
+        }
+    }
+    let usec = std::env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    if usec == 0 {
+        return None;
+    }
+    Some(Duration::from_micros(usec / 2))
+}
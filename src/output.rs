@@ -0,0 +1,464 @@
+// Captured stdout and stderr of worker processes
+use std;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::os::unix::io::{FromRawFd, RawFd};
+
+use actix::prelude::*;
+use bytes::BytesMut;
+use nix::unistd::Pid;
+use time;
+use tokio::codec::{Decoder, FramedRead};
+use tokio::reactor::PollEvented2;
+
+use config::{OutputSink, RotateInterval, ServiceConfig};
+use io::Io;
+use logging;
+use master_types::LogLine;
+use reaper;
+use service::{self, FeService};
+
+/// Lines longer than this are split, line has to fit into control socket frame
+/// even if every byte is escaped in JSON (`\u00XX`)
+const MAX_LINE: usize = 8192;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputStream {
+    Stdout,
+    Stderr,
+}
+
+impl OutputStream {
+    fn name(self) -> &'static str {
+        match self {
+            OutputStream::Stdout => "stdout",
+            OutputStream::Stderr => "stderr",
+        }
+    }
+}
+
+/// Destination of captured output of the service workers
+///
+/// Last `output_buffer` lines are kept in memory regardless of the sink,
+/// lines are also sent to clients that follow service output.
+pub struct Output {
+    name: String,
+    sink: Sink,
+    lines: VecDeque<LogLine>,
+    size: usize,
+    followers: Vec<(Option<usize>, Recipient<FollowLine>)>,
+}
+
+enum Sink {
+    Log,
+    File(LogFile),
+    Buffer,
+}
+
+/// Line of followed service output
+#[derive(Message)]
+pub struct FollowLine(pub LogLine);
+
+/// Output file, rotated by size or by time
+struct LogFile {
+    path: String,
+    file: std::fs::File,
+    size: u64,
+    period: (i32, i32, i32),
+    max_size: Option<u64>,
+    rotate: Option<RotateInterval>,
+    keep: usize,
+    compress: bool,
+    compressing: Option<Pid>,
+}
+
+impl LogFile {
+    fn open(cfg: &ServiceConfig, path: &str) -> Result<LogFile, io::Error> {
+        let file = LogFile::open_file(path)?;
+        Ok(LogFile {
+            size: file.metadata()?.len(),
+            period: LogFile::period(cfg.output_rotate),
+            path: path.to_owned(),
+            max_size: cfg.output_max_size,
+            rotate: cfg.output_rotate,
+            keep: cfg.output_keep,
+            compress: cfg.output_compress,
+            compressing: None,
+            file,
+        })
+    }
+
+    fn open_file(path: &str) -> Result<std::fs::File, io::Error> {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+    }
+
+    // rotation period of current time
+    fn period(rotate: Option<RotateInterval>) -> (i32, i32, i32) {
+        let tm = time::now();
+        match rotate {
+            Some(RotateInterval::Hourly) => (tm.tm_year, tm.tm_yday, tm.tm_hour),
+            Some(RotateInterval::Daily) => (tm.tm_year, tm.tm_yday, 0),
+            None => (0, 0, 0),
+        }
+    }
+
+    fn write(&mut self, line: &str) -> Result<(), io::Error> {
+        let len = line.len() as u64;
+        let oversized = match self.max_size {
+            Some(max) => self.size > 0 && self.size + len > max,
+            None => false,
+        };
+        if oversized || LogFile::period(self.rotate) != self.period {
+            self.rotate()?;
+        }
+        self.file.write_all(line.as_ref())?;
+        self.size += len;
+        Ok(())
+    }
+
+    /// Reopen file, i.e. after it is moved by external tool
+    fn reopen(&mut self) -> Result<(), io::Error> {
+        self.file = LogFile::open_file(&self.path)?;
+        self.size = self.file.metadata()?.len();
+        Ok(())
+    }
+
+    // rename `path` to `path.1`, `path.1` to `path.2` and so on,
+    // files over retention count get removed
+    fn rotate(&mut self) -> Result<(), io::Error> {
+        // `path.1` is still being compressed, rotation is retried on next write
+        if let Some(pid) = self.compressing {
+            if reaper::is_helper(pid) {
+                return Ok(());
+            }
+            self.compressing = None;
+        }
+
+        let ext = if self.compress { ".gz" } else { "" };
+        let name = |n: usize| format!("{}.{}{}", self.path, n, ext);
+
+        if self.keep == 0 {
+            let _ = std::fs::remove_file(&self.path);
+        } else {
+            let _ = std::fs::remove_file(name(self.keep));
+            for n in (1..self.keep).rev() {
+                let _ = std::fs::rename(name(n), name(n + 1));
+            }
+            let rotated = format!("{}.1", self.path);
+            if let Err(err) = std::fs::rename(&self.path, &rotated) {
+                // file could be removed by external tool
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err);
+                }
+            }
+            // gzip process gets reaped by command center
+            if self.compress {
+                match std::process::Command::new("gzip")
+                    .arg("-f")
+                    .arg(&rotated)
+                    .spawn()
+                {
+                    Ok(child) => {
+                        let pid = Pid::from_raw(child.id() as i32);
+                        reaper::add_helper(pid);
+                        self.compressing = Some(pid);
+                    }
+                    Err(err) => error!("Can not compress {}: {}", rotated, err),
+                }
+            }
+        }
+        self.period = LogFile::period(self.rotate);
+        self.reopen()
+    }
+}
+
+impl Output {
+    pub fn new(cfg: &ServiceConfig) -> Output {
+        let sink = match cfg.output {
+            OutputSink::Log => Sink::Log,
+            OutputSink::Buffer => Sink::Buffer,
+            OutputSink::File(ref path) => match LogFile::open(cfg, path) {
+                Ok(file) => Sink::File(file),
+                Err(err) => {
+                    error!(
+                        "Can not open output file {} of {:?}: {}, using master log",
+                        path, cfg.name, err
+                    );
+                    Sink::Log
+                }
+            },
+        };
+        Output {
+            name: cfg.name.clone(),
+            sink,
+            lines: VecDeque::new(),
+            size: cfg.output_buffer,
+            followers: Vec::new(),
+        }
+    }
+
+    /// Route line of worker output to the sink
+    pub fn write(&mut self, idx: usize, pid: Pid, stream: OutputStream, line: &str) {
+        match self.sink {
+            Sink::Log => {
+                let fields = vec![
+                    ("service".to_owned(), self.name.clone()),
+                    ("worker_idx".to_owned(), (idx + 1).to_string()),
+                    ("pid".to_owned(), pid.to_string()),
+                    ("stream".to_owned(), stream.name().to_owned()),
+                ];
+                logging::with_fields(fields, || match stream {
+                    OutputStream::Stdout => info!(
+                        "{} worker({}) (pid:{}) stdout: {}",
+                        self.name,
+                        idx + 1,
+                        pid,
+                        line
+                    ),
+                    OutputStream::Stderr => warn!(
+                        "{} worker({}) (pid:{}) stderr: {}",
+                        self.name,
+                        idx + 1,
+                        pid,
+                        line
+                    ),
+                })
+            }
+            Sink::File(ref mut file) => {
+                let line = format!(
+                    "{} worker({}) (pid:{}) {}: {}\n",
+                    self.name,
+                    idx + 1,
+                    pid,
+                    stream.name(),
+                    line
+                );
+                if let Err(err) = file.write(&line) {
+                    error!("Can not write output of {:?}: {}", self.name, err);
+                }
+            }
+            Sink::Buffer => (),
+        }
+
+        let line = LogLine {
+            worker: idx + 1,
+            pid: pid.to_string(),
+            stream: stream.name().to_owned(),
+            line: line.to_owned(),
+        };
+
+        // followers are removed once client connection is closed
+        self.followers.retain(|&(worker, ref rcp)| match worker {
+            Some(worker) if worker != line.worker => true,
+            _ => rcp.do_send(FollowLine(line.clone())).is_ok(),
+        });
+
+        if self.size > 0 {
+            if self.lines.len() >= self.size {
+                self.lines.pop_front();
+            }
+            self.lines.push_back(line);
+        }
+    }
+
+    /// Reopen output file
+    pub fn reopen(&mut self) {
+        if let Sink::File(ref mut file) = self.sink {
+            if let Err(err) = file.reopen() {
+                error!("Can not reopen output file {}: {}", file.path, err);
+            }
+        }
+    }
+
+    /// Last `num` buffered lines of all workers or of specific worker,
+    /// all buffered lines if `num` is not set
+    pub fn tail(&self, worker: Option<usize>, num: Option<usize>) -> Vec<LogLine> {
+        let mut lines: Vec<_> = self
+            .lines
+            .iter()
+            .rev()
+            .filter(|line| worker.map(|w| w == line.worker).unwrap_or(true))
+            .take(num.unwrap_or(self.size))
+            .cloned()
+            .collect();
+        lines.reverse();
+        lines
+    }
+
+    /// Send new lines of all workers or of specific worker to `rcp`
+    pub fn follow(&mut self, worker: Option<usize>, rcp: Recipient<FollowLine>) {
+        self.followers.push((worker, rcp));
+    }
+}
+
+#[derive(Message)]
+struct OutputLine(OutputStream, String);
+
+/// Reads output pipes of the worker process
+///
+/// Reader lives until worker and its subprocesses close pipes,
+/// so output of the exited worker is not lost.
+pub struct OutputReader {
+    idx: usize,
+    pid: Pid,
+    addr: Addr<FeService>,
+}
+
+impl Actor for OutputReader {
+    type Context = Context<Self>;
+}
+
+impl OutputReader {
+    pub fn start(idx: usize, pid: Pid, fds: (RawFd, RawFd), addr: Addr<FeService>) {
+        OutputReader::create(move |ctx| {
+            for &(fd, stream) in
+                &[(fds.0, OutputStream::Stdout), (fds.1, OutputStream::Stderr)]
+            {
+                let io = PollEvented2::new(unsafe { Io::from_raw_fd(fd) });
+                ctx.add_stream(FramedRead::new(io, OutputCodec(stream)));
+            }
+            OutputReader { idx, pid, addr }
+        });
+    }
+}
+
+impl StreamHandler<OutputLine, io::Error> for OutputReader {
+    fn handle(&mut self, msg: OutputLine, _: &mut Context<Self>) {
+        self.addr
+            .do_send(service::ProcessOutput(self.idx, self.pid, msg.0, msg.1));
+    }
+
+    // reader stops when all pipes are closed
+    fn finished(&mut self, _: &mut Context<Self>) {}
+}
+
+struct OutputCodec(OutputStream);
+
+impl Decoder for OutputCodec {
+    type Item = OutputLine;
+    type Error = io::Error;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let len = std::cmp::min(src.len(), MAX_LINE + 1);
+        let size = match src[..len].iter().position(|b| *b == b'\n') {
+            Some(pos) => pos + 1,
+            None if len > MAX_LINE => {
+                // do not split multibyte character
+                let boundary = (MAX_LINE - 3..=MAX_LINE)
+                    .rev()
+                    .find(|idx| src[*idx] & 0xC0 != 0x80);
+                boundary.unwrap_or(MAX_LINE)
+            }
+            None => return Ok(None),
+        };
+        let line = src.split_to(size);
+        let line = String::from_utf8_lossy(&line);
+        Ok(Some(OutputLine(
+            self.0,
+            line.trim_end_matches(&['\n', '\r'][..]).to_owned(),
+        )))
+    }
+
+    fn decode_eof(
+        &mut self, src: &mut BytesMut,
+    ) -> Result<Option<Self::Item>, Self::Error> {
+        match self.decode(src)? {
+            Some(line) => Ok(Some(line)),
+            None if src.is_empty() => Ok(None),
+            None => {
+                let line = src.take();
+                let line = String::from_utf8_lossy(&line).into_owned();
+                Ok(Some(OutputLine(self.0, line)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn line(item: Option<OutputLine>) -> Option<(OutputStream, String)> {
+        item.map(|line| (line.0, line.1))
+    }
+
+    #[test]
+    fn test_codec_lines() {
+        let mut codec = OutputCodec(OutputStream::Stdout);
+        let mut src = BytesMut::from(&b"first\nsecond\r\nthi"[..]);
+
+        let item = line(codec.decode(&mut src).unwrap());
+        assert_eq!(item, Some((OutputStream::Stdout, "first".to_owned())));
+        let item = line(codec.decode(&mut src).unwrap());
+        assert_eq!(item, Some((OutputStream::Stdout, "second".to_owned())));
+
+        // incomplete line waits for more data
+        assert!(codec.decode(&mut src).unwrap().is_none());
+        src.extend_from_slice(b"rd\n");
+        let item = line(codec.decode(&mut src).unwrap());
+        assert_eq!(item, Some((OutputStream::Stdout, "third".to_owned())));
+        assert!(src.is_empty());
+    }
+
+    #[test]
+    fn test_codec_long_line() {
+        let mut codec = OutputCodec(OutputStream::Stderr);
+        let mut src = BytesMut::from(vec![b'a'; MAX_LINE + 10]);
+
+        let item = line(codec.decode(&mut src).unwrap()).unwrap();
+        assert_eq!(item.0, OutputStream::Stderr);
+        assert_eq!(item.1.len(), MAX_LINE);
+        assert_eq!(src.len(), 10);
+        assert!(codec.decode(&mut src).unwrap().is_none());
+
+        // newline beyond max line size does not extend line
+        let mut src = BytesMut::from(vec![b'a'; MAX_LINE + 10]);
+        src.extend_from_slice(b"\n");
+        let item = line(codec.decode(&mut src).unwrap()).unwrap();
+        assert_eq!(item.1.len(), MAX_LINE);
+        let item = line(codec.decode(&mut src).unwrap()).unwrap();
+        assert_eq!(item.1.len(), 10);
+    }
+
+    #[test]
+    fn test_codec_long_line_char_boundary() {
+        let mut codec = OutputCodec(OutputStream::Stdout);
+        let mut src = BytesMut::from(vec![b'a'; MAX_LINE - 1]);
+        src.extend_from_slice("\u{20ac}b\n".as_bytes());
+
+        let item = line(codec.decode(&mut src).unwrap()).unwrap();
+        assert_eq!(item.1.len(), MAX_LINE - 1);
+        assert!(item.1.bytes().all(|b| b == b'a'));
+        let item = line(codec.decode(&mut src).unwrap());
+        assert_eq!(item, Some((OutputStream::Stdout, "\u{20ac}b".to_owned())));
+    }
+
+    #[test]
+    fn test_codec_eof() {
+        let mut codec = OutputCodec(OutputStream::Stdout);
+        let mut src = BytesMut::from(&b"line\nrest"[..]);
+
+        let item = line(codec.decode_eof(&mut src).unwrap());
+        assert_eq!(item, Some((OutputStream::Stdout, "line".to_owned())));
+
+        // remaining data without newline is returned on eof
+        let item = line(codec.decode_eof(&mut src).unwrap());
+        assert_eq!(item, Some((OutputStream::Stdout, "rest".to_owned())));
+        assert!(codec.decode_eof(&mut src).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_codec_invalid_utf8() {
+        let mut codec = OutputCodec(OutputStream::Stdout);
+        let mut src = BytesMut::from(&b"bad \xff\n"[..]);
+
+        let item = line(codec.decode(&mut src).unwrap());
+        assert_eq!(
+            item,
+            Some((OutputStream::Stdout, "bad \u{fffd}".to_owned()))
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use std;
+use std::collections::HashMap;
+use std::ffi::{OsStr, OsString};
+use std::io::{self, Read, Write};
+
+use serde_json as json;
+
+/// Master state that survives master restarts
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct MasterState {
+    /// Master process is running, set on start and cleared on exit
+    #[serde(default)]
+    pub running: bool,
+    #[serde(default)]
+    pub services: HashMap<String, ServiceRecord>,
+}
+
+/// Persisted service state
+#[derive(Serialize, Deserialize, Default, Debug)]
+pub struct ServiceRecord {
+    /// Runtime number of workers
+    #[serde(default)]
+    pub num: Option<u16>,
+    /// Active release
+    #[serde(default)]
+    pub release: Option<String>,
+    /// Previously active release
+    #[serde(default)]
+    pub previous: Option<String>,
+    /// Runtime service state
+    #[serde(default)]
+    pub journal: ServiceJournal,
+}
+
+/// Runtime service state, journaled on every change
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct ServiceJournal {
+    /// Runtime number of workers
+    pub num: usize,
+    /// Service is stopped with `fectl stop`
+    pub stopped: bool,
+    pub paused: bool,
+    pub workers: Vec<WorkerRecord>,
+}
+
+#[derive(Serialize, Deserialize, Default, Clone, PartialEq, Debug)]
+pub struct WorkerRecord {
+    pub idx: usize,
+    /// Number of processes started by worker
+    pub generation: u64,
+    /// Pids of worker processes
+    pub pids: Vec<i32>,
+}
+
+impl MasterState {
+    /// Load state from file, missing or broken file results in empty state
+    pub fn load(path: &OsStr) -> MasterState {
+        let mut buf = Vec::new();
+        match std::fs::File::open(path).and_then(|mut f| f.read_to_end(&mut buf)) {
+            Ok(_) => match json::from_slice(&buf) {
+                Ok(state) => state,
+                Err(err) => {
+                    warn!("Can not parse state file {:?}: {}", path, err);
+                    MasterState::default()
+                }
+            },
+            Err(ref err) if err.kind() == io::ErrorKind::NotFound => {
+                MasterState::default()
+            }
+            Err(err) => {
+                warn!("Can not read state file {:?}: {}", path, err);
+                MasterState::default()
+            }
+        }
+    }
+
+    /// Atomically save state to file
+    pub fn save(&self, path: &OsStr) -> Result<(), io::Error> {
+        let mut tmp = OsString::from(path);
+        tmp.push(".tmp");
+
+        {
+            let mut file = std::fs::File::create(&tmp)?;
+            file.write_all(json::to_string(self)?.as_ref())?;
+            file.sync_all()?;
+        }
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Runtime number of workers for service
+    pub fn num(&self, name: &str) -> Option<u16> {
+        self.services.get(name).and_then(|srv| srv.num)
+    }
+
+    /// Active and previous release of service
+    pub fn release(&self, name: &str) -> (Option<String>, Option<String>) {
+        match self.services.get(name) {
+            Some(srv) => (srv.release.clone(), srv.previous.clone()),
+            None => (None, None),
+        }
+    }
+
+    /// Store active and previous release of service
+    pub fn set_release(
+        &mut self, name: &str, release: Option<String>, previous: Option<String>,
+    ) {
+        let srv = self.services.entry(name.to_owned()).or_default();
+        srv.release = release;
+        srv.previous = previous;
+    }
+
+    /// Journaled runtime state of service
+    pub fn journal(&self, name: &str) -> ServiceJournal {
+        self.services
+            .get(name)
+            .map(|srv| srv.journal.clone())
+            .unwrap_or_default()
+    }
+
+    /// Store runtime state of service
+    pub fn set_journal(&mut self, name: &str, journal: ServiceJournal) {
+        self.services.entry(name.to_owned()).or_default().journal = journal;
+    }
+
+    /// Store runtime number of workers for service
+    pub fn set_num(&mut self, name: &str, num: u16) {
+        self.services.entry(name.to_owned()).or_default().num = Some(num);
+    }
+}
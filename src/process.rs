@@ -1,6 +1,7 @@
 #![allow(dead_code)]
 
 use std;
+use std::collections::{BTreeMap, VecDeque};
 use std::error::Error;
 use std::io;
 use std::os::unix::io::RawFd;
@@ -8,7 +9,7 @@ use std::time::{Duration, Instant};
 
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{BufMut, BytesMut};
-use nix::sys::signal::{kill, Signal};
+use nix::sys::signal::{kill, killpg, Signal};
 use nix::unistd::{close, fork, pipe, ForkResult, Pid};
 use serde_json as json;
 use tokio::codec::{Decoder, Encoder, FramedRead};
@@ -16,11 +17,14 @@ use tokio::io::{AsyncRead, WriteHalf};
 
 use actix::prelude::*;
 
-use config::ServiceConfig;
+use config::{KillMode, KillStep, ServiceConfig};
 use event::Reason;
 use exec::exec_worker;
 use io::PipeFile;
+use logging;
+use output::OutputReader;
 use service::{self, FeService};
+use upgrade::set_cloexec;
 use worker::{WorkerCommand, WorkerMessage};
 
 const HEARTBEAT: u64 = 2;
@@ -29,14 +33,21 @@ pub const WORKER_INIT_FAILED: i32 = 99;
 pub const WORKER_BOOT_FAILED: i32 = 100;
 
 pub struct Process {
+    name: String,
     idx: usize,
     pid: Pid,
+    fds: (RawFd, RawFd),
+    output: Option<(RawFd, RawFd)>,
     state: ProcessState,
     hb: Instant,
     addr: Addr<FeService>,
     timeout: Duration,
     startup_timeout: u64,
-    shutdown_timeout: u64,
+    stop_sequence: Vec<KillStep>,
+    reload_sequence: Vec<KillStep>,
+    stop_steps: VecDeque<KillStep>,
+    quit_step: Option<KillStep>,
+    kill_mode: KillMode,
     framed: actix::io::FramedWrite<WriteHalf<PipeFile>, TransportCodec>,
 }
 
@@ -137,7 +148,7 @@ impl Process {
         idx: usize, cfg: &ServiceConfig, addr: Addr<FeService>,
     ) -> (Pid, Option<Addr<Process>>) {
         // fork process and esteblish communication
-        let (pid, pipe) = match Process::fork(idx, cfg) {
+        let (pid, fds, output) = match Process::fork(idx, cfg) {
             Ok(res) => res,
             Err(err) => {
                 let pid = Pid::from_raw(-1);
@@ -151,43 +162,131 @@ impl Process {
             }
         };
 
+        let addr =
+            Process::run(idx, pid, fds, output, cfg, addr, ProcessState::Starting);
+        (pid, Some(addr))
+    }
+
+    /// Adopt running worker process of the previous master process
+    pub fn adopt(
+        idx: usize, pid: Pid, fds: (RawFd, RawFd), output: Option<(RawFd, RawFd)>,
+        cfg: &ServiceConfig, addr: Addr<FeService>,
+    ) -> Addr<Process> {
+        Process::run(idx, pid, fds, output, cfg, addr, ProcessState::Running)
+    }
+
+    fn run(
+        idx: usize, pid: Pid, fds: (RawFd, RawFd), output: Option<(RawFd, RawFd)>,
+        cfg: &ServiceConfig, addr: Addr<FeService>, state: ProcessState,
+    ) -> Addr<Process> {
         let timeout = Duration::new(u64::from(cfg.timeout), 0);
         let startup_timeout = u64::from(cfg.startup_timeout);
-        let shutdown_timeout = u64::from(cfg.shutdown_timeout);
+        let stop_sequence = cfg.stop_sequence(false);
+        let reload_sequence = cfg.stop_sequence(true);
+        let quit_step = cfg.kill_escalation.last().cloned();
+        let kill_mode = cfg.kill_mode;
+        let name = cfg.name.clone();
+
+        // captured output is read until all writers close pipes
+        if let Some(output) = output {
+            OutputReader::start(idx, pid, output, addr.clone());
+        }
 
         // start Process service
-        let addr = Process::create(move |ctx| {
+        Process::create(move |ctx| {
+            let pipe = PipeFile::new(fds.0, fds.1);
             let (r, w) = pipe.split();
             ctx.add_stream(FramedRead::new(r, TransportCodec));
-            ctx.notify_later(
-                ProcessMessage::StartupTimeout,
-                Duration::new(startup_timeout as u64, 0),
-            );
+            match state {
+                ProcessState::Running => ctx.notify_later(
+                    ProcessMessage::Heartbeat,
+                    Duration::new(HEARTBEAT, 0),
+                ),
+                _ => ctx.notify_later(
+                    ProcessMessage::StartupTimeout,
+                    Duration::new(startup_timeout as u64, 0),
+                ),
+            };
             Process {
+                name,
                 idx,
                 pid,
+                fds,
+                output,
                 addr,
                 timeout,
                 startup_timeout,
-                shutdown_timeout,
-                state: ProcessState::Starting,
+                stop_sequence,
+                reload_sequence,
+                stop_steps: VecDeque::new(),
+                quit_step,
+                kill_mode,
+                state,
                 hb: Instant::now(),
                 framed: actix::io::FramedWrite::new(w, TransportCodec, ctx),
             }
+        })
+    }
+
+    /// Emit log record of worker process, record is tagged with service,
+    /// worker and pid fields
+    fn log(&self, level: &str, msg: &str, fields: BTreeMap<String, json::Value>) {
+        let mut fields: Vec<_> = fields
+            .into_iter()
+            .filter(|&(ref name, _)| {
+                name != "service" && name != "worker_idx" && name != "pid"
+            }).map(|(name, value)| match value {
+                json::Value::String(value) => (name, value),
+                value => (name, value.to_string()),
+            }).collect();
+        fields.push(("service".to_owned(), self.name.clone()));
+        fields.push(("worker_idx".to_owned(), (self.idx + 1).to_string()));
+        fields.push(("pid".to_owned(), self.pid.to_string()));
+
+        logging::with_fields(fields, || {
+            log!(
+                logging::worker_level(level),
+                "{} worker({}) (pid:{}): {}",
+                self.name,
+                self.idx + 1,
+                self.pid,
+                msg
+            )
         });
-        (pid, Some(addr))
     }
 
-    fn fork(idx: usize, cfg: &ServiceConfig) -> Result<(Pid, PipeFile), io::Error> {
+    fn fork(
+        idx: usize, cfg: &ServiceConfig,
+    ) -> Result<(Pid, (RawFd, RawFd), Option<(RawFd, RawFd)>), io::Error> {
         let (p_read, p_write, ch_read, ch_write) = Process::create_pipes()?;
 
+        // pipes for captured stdout and stderr
+        let output = if cfg.capture_output {
+            match Process::create_pipes() {
+                Ok(pipes) => Some(pipes),
+                Err(err) => {
+                    for fd in &[p_read, p_write, ch_read, ch_write] {
+                        let _ = close(*fd);
+                    }
+                    return Err(err);
+                }
+            }
+        } else {
+            None
+        };
+
         // fork
         let pid = match fork() {
             Ok(ForkResult::Parent { child }) => child,
             Ok(ForkResult::Child) => {
                 let _ = close(p_write);
                 let _ = close(ch_read);
-                exec_worker(idx, cfg, p_read, ch_write);
+                let output = output.map(|(out_read, out_write, err_read, err_write)| {
+                    let _ = close(out_read);
+                    let _ = close(err_read);
+                    (out_write, err_write)
+                });
+                exec_worker(idx, cfg, p_read, ch_write, output);
                 unreachable!();
             }
             Err(err) => {
@@ -199,9 +298,21 @@ impl Process {
         // initialize worker communication channel
         let _ = close(p_read);
         let _ = close(ch_write);
-        let pipe = PipeFile::new(ch_read, p_write);
+        let output = output.map(|(out_read, out_write, err_read, err_write)| {
+            let _ = close(out_write);
+            let _ = close(err_write);
+            (out_read, err_read)
+        });
+
+        // master ends of pipes must not leak into other workers
+        set_cloexec(ch_read, true);
+        set_cloexec(p_write, true);
+        if let Some((out_read, err_read)) = output {
+            set_cloexec(out_read, true);
+            set_cloexec(err_read, true);
+        }
 
-        Ok((pid, pipe))
+        Ok((pid, (ch_read, p_write), output))
     }
 
     fn create_pipes() -> Result<(RawFd, RawFd, RawFd, RawFd), io::Error> {
@@ -229,11 +340,60 @@ impl Process {
         Ok((p_read, p_write, ch_read, ch_write))
     }
 
+    // send signal to worker process or to process group of the worker
+    fn signal(&self, signal: Signal) {
+        match self.kill_mode {
+            KillMode::Process => {
+                let _ = kill(self.pid, signal);
+            }
+            KillMode::Group => {
+                // worker could exit before creating new session
+                if killpg(self.pid, signal).is_err() {
+                    let _ = kill(self.pid, signal);
+                }
+            }
+        }
+    }
+
+    // start stop sequence, process is killed if it is still alive after last step
+    fn stop_sequence(&mut self, reload: bool, ctx: &mut Context<Self>) {
+        self.state = ProcessState::Stopping;
+        let steps = if reload {
+            &self.reload_sequence
+        } else {
+            &self.stop_sequence
+        };
+        self.stop_steps = steps.iter().cloned().collect();
+        if !self.stop_step(ctx) {
+            self.kill(ctx, false);
+        }
+    }
+
+    // send signal of next stop sequence step
+    fn stop_step(&mut self, ctx: &mut Context<Self>) -> bool {
+        if let Some(step) = self.stop_steps.pop_front() {
+            info!(
+                "Sending {:?} to worker (pid:{}), wait {} secs",
+                step.signal, self.pid, step.timeout
+            );
+            self.signal(step.signal);
+            self.addr
+                .do_send(service::ProcessSignaled(self.idx, self.pid, step.signal));
+            ctx.notify_later(
+                ProcessMessage::StopTimeout,
+                Duration::new(u64::from(step.timeout), 0),
+            );
+            true
+        } else {
+            false
+        }
+    }
+
     fn kill(&self, ctx: &mut Context<Self>, graceful: bool) {
         if graceful {
             ctx.notify_later(ProcessMessage::Kill, Duration::new(1, 0));
         } else {
-            let _ = kill(self.pid, Signal::SIGKILL);
+            self.signal(Signal::SIGKILL);
             ctx.terminate();
         }
     }
@@ -241,7 +401,7 @@ impl Process {
 
 impl Drop for Process {
     fn drop(&mut self) {
-        let _ = kill(self.pid, Signal::SIGKILL);
+        self.signal(Signal::SIGKILL);
     }
 }
 
@@ -301,6 +461,16 @@ impl Handler<ProcessMessage> for Process {
                         WorkerMessage::restart,
                     ));
                 }
+                WorkerMessage::load(load) => {
+                    self.addr.do_send(service::ProcessMessage(
+                        self.idx,
+                        self.pid,
+                        WorkerMessage::load(load),
+                    ));
+                }
+                WorkerMessage::log { level, msg, fields } => {
+                    self.log(&level, &msg, fields)
+                }
                 WorkerMessage::cfgerror(msg) => {
                     error!("Worker config error: {} (pid:{})", msg, self.pid);
                     self.addr.do_send(service::ProcessFailed(
@@ -320,17 +490,19 @@ impl Handler<ProcessMessage> for Process {
                     ));
 
                     self.state = ProcessState::Failed;
-                    let _ = kill(self.pid, Signal::SIGKILL);
+                    self.signal(Signal::SIGKILL);
                     ctx.stop();
                     return;
                 }
             }
             ProcessMessage::StopTimeout => {
                 if let ProcessState::Stopping = self.state {
-                    info!(
-                        "Worker shutdown timeout aftre {} secs",
-                        self.shutdown_timeout
-                    );
+                    // escalate to next step
+                    if self.stop_step(ctx) {
+                        return;
+                    }
+
+                    info!("Worker shutdown timeout (pid:{})", self.pid);
                     self.addr.do_send(service::ProcessFailed(
                         self.idx,
                         self.pid,
@@ -338,7 +510,7 @@ impl Handler<ProcessMessage> for Process {
                     ));
 
                     self.state = ProcessState::Failed;
-                    let _ = kill(self.pid, Signal::SIGKILL);
+                    self.signal(Signal::SIGKILL);
                     ctx.stop();
                     return;
                 }
@@ -368,7 +540,18 @@ impl Handler<ProcessMessage> for Process {
                 }
             }
             ProcessMessage::Kill => {
-                let _ = kill(self.pid, Signal::SIGKILL);
+                // last step of kill escalation is sent before `KILL`
+                if let Some(step) = self.quit_step.take() {
+                    if step.signal != Signal::SIGKILL {
+                        self.signal(step.signal);
+                        ctx.notify_later(
+                            ProcessMessage::Kill,
+                            Duration::new(u64::from(step.timeout), 0),
+                        );
+                        return;
+                    }
+                }
+                self.signal(Signal::SIGKILL);
                 ctx.stop();
                 return;
             }
@@ -420,33 +603,44 @@ impl Handler<ResumeProcess> for Process {
     }
 }
 
+/// Gracefully stop process, flag is set if process is replaced during reload
 #[derive(Message)]
-pub struct StopProcess;
+pub struct StopProcess(pub bool);
 
 impl Handler<StopProcess> for Process {
     type Result = ();
 
-    fn handle(&mut self, _: StopProcess, ctx: &mut Context<Process>) {
+    fn handle(&mut self, msg: StopProcess, ctx: &mut Context<Process>) {
         info!("Stopping worker: (pid:{})", self.pid);
         match self.state {
             ProcessState::Running => {
-                self.state = ProcessState::Stopping;
-
                 self.framed.write(WorkerCommand::stop);
-                ctx.notify_later(
-                    ProcessMessage::StopTimeout,
-                    Duration::new(self.shutdown_timeout, 0),
-                );
-                let _ = kill(self.pid, Signal::SIGTERM);
-            }
-            _ => {
-                let _ = kill(self.pid, Signal::SIGQUIT);
-                ctx.terminate();
+                self.stop_sequence(msg.0, ctx);
             }
+            // worker does not handle `stop` command yet
+            ProcessState::Starting => self.stop_sequence(msg.0, ctx),
+            ProcessState::Stopping => (),
+            ProcessState::Failed => self.kill(ctx, false),
         }
     }
 }
 
+/// Communication and output pipes of the process, handed over to upgraded
+/// master process
+pub struct Handover;
+
+impl Message for Handover {
+    type Result = ((RawFd, RawFd), Option<(RawFd, RawFd)>);
+}
+
+impl Handler<Handover> for Process {
+    type Result = MessageResult<Handover>;
+
+    fn handle(&mut self, _: Handover, _: &mut Context<Process>) -> Self::Result {
+        MessageResult((self.fds, self.output))
+    }
+}
+
 #[derive(Message)]
 pub struct QuitProcess(pub bool);
 
@@ -455,12 +649,11 @@ impl Handler<QuitProcess> for Process {
 
     fn handle(&mut self, msg: QuitProcess, ctx: &mut Context<Process>) {
         if msg.0 {
-            let _ = kill(self.pid, Signal::SIGQUIT);
+            // fast exit, worker is killed after 1 sec
+            self.signal(Signal::SIGQUIT);
             self.kill(ctx, true);
         } else {
             self.kill(ctx, false);
-            let _ = kill(self.pid, Signal::SIGKILL);
-            ctx.terminate();
         }
     }
 }
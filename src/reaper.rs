@@ -0,0 +1,236 @@
+// Orphaned worker subprocesses
+use std;
+use std::cell::RefCell;
+use std::collections::{HashSet, VecDeque};
+use std::io::Read;
+use std::time::{Duration, Instant};
+
+use libc;
+use nix::sys::signal::{kill, killpg, Signal};
+use nix::unistd::{getpid, Pid};
+
+use config::{KillMode, KillStep, ServiceConfig};
+
+/// Process that was reparented to master process
+#[derive(Debug)]
+pub struct Orphan {
+    pub pid: Pid,
+    pub pgid: Pid,
+    pub sid: Pid,
+    pub cmd: String,
+}
+
+/// Environment variable with service name of the worker process
+pub const SERVICE_VAR: &str = "FECTL_SRV_NAME";
+
+/// Environment variable that identifies master process of the worker,
+/// set to path of the lock file
+pub const MASTER_VAR: &str = "FECTL_MASTER";
+
+thread_local! {
+    // children spawned by master itself, i.e. canary probes
+    static HELPERS: RefCell<HashSet<Pid>> = RefCell::new(HashSet::new());
+}
+
+/// Register child process spawned by master, helpers are never orphans
+pub fn add_helper(pid: Pid) {
+    HELPERS.with(|helpers| helpers.borrow_mut().insert(pid));
+}
+
+/// Child process of master exited
+pub fn exited(pid: Pid) {
+    HELPERS.with(|helpers| helpers.borrow_mut().remove(&pid));
+}
+
+/// Check if helper process is still running
+pub fn is_helper(pid: Pid) -> bool {
+    HELPERS.with(|helpers| helpers.borrow().contains(&pid))
+}
+
+/// Make master process subreaper, so orphaned worker subprocesses
+/// get reparented to master process instead of init.
+#[cfg(target_os = "linux")]
+pub fn set_subreaper() {
+    let res = unsafe { libc::prctl(libc::PR_SET_CHILD_SUBREAPER, 1, 0, 0, 0) };
+    if res != 0 {
+        warn!(
+            "Can not set child subreaper: {}",
+            std::io::Error::last_os_error()
+        );
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn set_subreaper() {}
+
+/// Children of master process that are not workers
+pub fn orphans(workers: &HashSet<Pid>) -> Vec<Orphan> {
+    let master = getpid();
+    let entries = match std::fs::read_dir("/proc") {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut orphans = Vec::new();
+    for entry in entries.filter_map(|e| e.ok()) {
+        let pid = match entry.file_name().to_str().and_then(|s| s.parse().ok()) {
+            Some(pid) => Pid::from_raw(pid),
+            None => continue,
+        };
+        if workers.contains(&pid) || HELPERS.with(|h| h.borrow().contains(&pid)) {
+            continue;
+        }
+        if let Some((ppid, pgid, sid, cmd)) = stat(pid) {
+            if ppid == master {
+                orphans.push(Orphan {
+                    pid,
+                    pgid,
+                    sid,
+                    cmd,
+                });
+            }
+        }
+    }
+    orphans
+}
+
+/// Worker processes that survived crash of previous master process
+///
+/// Each step of the stop sequence is applied to processes that are still alive,
+/// remaining processes get killed.
+pub struct Survivors {
+    name: String,
+    mode: KillMode,
+    pids: Vec<Pid>,
+    steps: VecDeque<KillStep>,
+    deadline: Instant,
+}
+
+impl Survivors {
+    /// Surviving workers of the service, `None` if all processes are gone
+    pub fn new(cfg: &ServiceConfig, pids: &[Pid]) -> Option<Survivors> {
+        // pid could be reused by unrelated process or by worker of other master
+        let master = std::env::var(MASTER_VAR).ok();
+        let pids: Vec<Pid> = pids
+            .iter()
+            .cloned()
+            .filter(|pid| match environ(*pid) {
+                Some(env) => {
+                    var(&env, SERVICE_VAR) == Some(cfg.name.as_str())
+                        && var(&env, MASTER_VAR).map(|s| s.to_owned()) == master
+                }
+                None => false,
+            }).collect();
+        if pids.is_empty() {
+            return None;
+        }
+        warn!(
+            "Terminating {} surviving worker processes of {:?}",
+            pids.len(),
+            cfg.name
+        );
+
+        Some(Survivors {
+            pids,
+            name: cfg.name.clone(),
+            mode: cfg.kill_mode,
+            steps: cfg.stop_sequence(false).into_iter().collect(),
+            deadline: Instant::now(),
+        })
+    }
+
+    /// Send next signal of the stop sequence once timeout of previous step
+    /// is elapsed, returns `true` when all processes are gone
+    pub fn poll(&mut self) -> bool {
+        self.pids.retain(|pid| kill(*pid, None).is_ok());
+        if self.pids.is_empty() {
+            return true;
+        }
+        if Instant::now() < self.deadline {
+            return false;
+        }
+
+        match self.steps.pop_front() {
+            Some(step) => {
+                for pid in &self.pids {
+                    info!(
+                        "Sending {:?} to surviving worker of {:?} (pid:{})",
+                        step.signal, self.name, pid
+                    );
+                    signal(self.mode, *pid, step.signal);
+                }
+                self.deadline =
+                    Instant::now() + Duration::new(u64::from(step.timeout), 0);
+                false
+            }
+            None => {
+                for pid in &self.pids {
+                    warn!("Killing surviving worker of {:?} (pid:{})", self.name, pid);
+                    signal(self.mode, *pid, Signal::SIGKILL);
+                }
+                true
+            }
+        }
+    }
+}
+
+/// Send signal to process or to process group of the process
+pub fn signal(mode: KillMode, pid: Pid, signal: Signal) {
+    if let KillMode::Group = mode {
+        if killpg(pid, signal).is_ok() {
+            return;
+        }
+    }
+    let _ = kill(pid, signal);
+}
+
+/// Service name from process environment
+pub fn service_name(pid: Pid) -> Option<String> {
+    environ(pid).and_then(|env| var(&env, SERVICE_VAR).map(|s| s.to_owned()))
+}
+
+// environment variables of the process
+fn environ(pid: Pid) -> Option<Vec<String>> {
+    let mut buf = Vec::new();
+    std::fs::File::open(format!("/proc/{}/environ", pid))
+        .and_then(|mut f| f.read_to_end(&mut buf))
+        .ok()?;
+
+    Some(
+        buf.split(|c| *c == 0)
+            .filter_map(|var| std::str::from_utf8(var).ok())
+            .map(|var| var.to_owned())
+            .collect(),
+    )
+}
+
+// value of environment variable
+fn var<'a>(env: &'a [String], name: &str) -> Option<&'a str> {
+    env.iter()
+        .find(|var| var.starts_with(name) && var[name.len()..].starts_with('='))
+        .map(|var| &var[name.len() + 1..])
+}
+
+// parse parent pid, process group, session and command from `/proc/<pid>/stat`
+fn stat(pid: Pid) -> Option<(Pid, Pid, Pid, String)> {
+    let mut content = String::new();
+    std::fs::File::open(format!("/proc/{}/stat", pid))
+        .and_then(|mut f| f.read_to_string(&mut content))
+        .ok()?;
+
+    // command could contain spaces and parens
+    let start = content.find('(')?;
+    let end = content.rfind(')')?;
+    let cmd = content[start + 1..end].to_owned();
+
+    let mut fields = content[end + 1..].split_whitespace().skip(1);
+    let ppid = fields.next()?.parse().ok()?;
+    let pgid = fields.next()?.parse().ok()?;
+    let sid = fields.next()?.parse().ok()?;
+    Some((
+        Pid::from_raw(ppid),
+        Pid::from_raw(pgid),
+        Pid::from_raw(sid),
+        cmd,
+    ))
+}
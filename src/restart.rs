@@ -0,0 +1,291 @@
+use std::collections::VecDeque;
+use std::process;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use config::ServiceConfig;
+use process::ProcessError;
+
+/// Worker restart policy
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    /// Always restart exited worker
+    Always,
+    /// Restart worker only if it exits with failure
+    OnFailure,
+    /// Never restart exited worker
+    Never,
+    /// Same as `always`, but service that is stopped explicitly stays stopped,
+    /// also after master restart
+    UnlessStopped,
+}
+
+impl RestartPolicy {
+    /// Check if exited worker should be restarted
+    pub fn restart(self, success: bool) -> bool {
+        match self {
+            RestartPolicy::Always | RestartPolicy::UnlessStopped => true,
+            RestartPolicy::OnFailure => !success,
+            RestartPolicy::Never => false,
+        }
+    }
+
+    /// Check if explicitly stopped service stays stopped after master restart
+    pub fn keep_stopped(self) -> bool {
+        self == RestartPolicy::UnlessStopped
+    }
+}
+
+/// Check if worker exited successfully
+pub fn is_success(err: &ProcessError, codes: &[i32]) -> bool {
+    match *err {
+        ProcessError::ExitCode(code) => codes.contains(&i32::from(code as u8)),
+        _ => false,
+    }
+}
+
+/// Limits number of worker restarts within time interval
+pub struct StartLimit {
+    starts: VecDeque<Instant>,
+}
+
+impl StartLimit {
+    pub fn new() -> StartLimit {
+        StartLimit {
+            starts: VecDeque::new(),
+        }
+    }
+
+    /// Register restart, returns `false` if start limit is reached
+    pub fn check(&mut self, cfg: &ServiceConfig) -> bool {
+        if cfg.start_limit_burst == 0 {
+            return true;
+        }
+
+        let now = Instant::now();
+        let interval = Duration::new(u64::from(cfg.start_limit_interval), 0);
+        while let Some(start) = self.starts.front().cloned() {
+            if now.duration_since(start) > interval {
+                self.starts.pop_front();
+            } else {
+                break;
+            }
+        }
+
+        if self.starts.len() >= cfg.start_limit_burst as usize {
+            false
+        } else {
+            self.starts.push_back(now);
+            true
+        }
+    }
+
+    /// Reset start limit
+    pub fn reset(&mut self) {
+        self.starts.clear();
+    }
+}
+
+/// Per-worker restart backoff state
+///
+/// Every failed restart increases delay before next start attempt
+/// by `restart_delay_multiplier`, up to `restart_delay_max`. Random jitter
+/// spreads restarts of different workers, so workers do not restart in lockstep.
+/// Backoff state is reset once worker stays loaded for `restart_stable_period`.
+pub struct Backoff {
+    attempts: u32,
+    loaded: Option<Instant>,
+    seed: u64,
+}
+
+impl Backoff {
+    pub fn new(idx: usize) -> Backoff {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|d| u64::from(d.subsec_nanos()) ^ d.as_secs())
+            .unwrap_or(0);
+        let seed = nanos
+            ^ (idx as u64 + 1).wrapping_mul(0x9E37_79B9_7F4A_7C15)
+            ^ (u64::from(process::id()) << 32);
+
+        Backoff {
+            attempts: 0,
+            loaded: None,
+            seed: if seed == 0 {
+                0x2545_F491_4F6C_DD1D
+            } else {
+                seed
+            },
+        }
+    }
+
+    /// Worker reported `loaded` state
+    pub fn loaded(&mut self) {
+        self.loaded = Some(Instant::now());
+    }
+
+    /// Reset backoff state
+    pub fn reset(&mut self) {
+        self.attempts = 0;
+        self.loaded = None;
+    }
+
+    /// Calculate delay for next start attempt
+    pub fn next_delay(&mut self, cfg: &ServiceConfig) -> Duration {
+        // worker was stable long enough, start from the beginning
+        if let Some(loaded) = self.loaded.take() {
+            let stable = Duration::new(u64::from(cfg.restart_stable_period), 0);
+            if Instant::now().duration_since(loaded) >= stable {
+                self.attempts = 0;
+            }
+        }
+
+        let mut delay = f64::from(cfg.restart_delay)
+            * cfg
+                .restart_delay_multiplier
+                .max(1.0)
+                .powi(self.attempts as i32);
+        delay = delay.min(f64::from(cfg.restart_delay_max));
+
+        if cfg.restart_jitter > 0.0 {
+            let jitter = cfg.restart_jitter.min(1.0);
+            delay += delay * jitter * (self.random() * 2.0 - 1.0);
+        }
+        self.attempts = self.attempts.saturating_add(1);
+
+        if delay > 0.0 {
+            Duration::new(delay.trunc() as u64, (delay.fract() * 1e9) as u32)
+        } else {
+            Duration::new(0, 0)
+        }
+    }
+
+    // xorshift64*, uniform value in [0, 1)
+    fn random(&mut self) -> f64 {
+        self.seed ^= self.seed >> 12;
+        self.seed ^= self.seed << 25;
+        self.seed ^= self.seed >> 27;
+        let val = self.seed.wrapping_mul(0x2545_F491_4F6C_DD1D);
+        (val >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use toml;
+
+    fn config(extra: &str) -> ServiceConfig {
+        toml::from_str(&format!(
+            "name = \"test\"\nnum = 1\ncommand = \"true\"\n{}",
+            extra
+        )).unwrap()
+    }
+
+    fn secs(delay: Duration) -> f64 {
+        delay.as_secs() as f64 + f64::from(delay.subsec_nanos()) / 1e9
+    }
+
+    #[test]
+    fn test_backoff_grows_up_to_max() {
+        let cfg =
+            config("restart_delay = 1\nrestart_delay_max = 5\nrestart_jitter = 0.0");
+        let mut backoff = Backoff::new(0);
+
+        let delays: Vec<_> = (0..5).map(|_| backoff.next_delay(&cfg)).collect();
+        assert_eq!(
+            delays,
+            vec![
+                Duration::new(1, 0),
+                Duration::new(2, 0),
+                Duration::new(4, 0),
+                Duration::new(5, 0),
+                Duration::new(5, 0),
+            ]
+        );
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(&cfg), Duration::new(1, 0));
+    }
+
+    #[test]
+    fn test_backoff_zero_delay() {
+        let cfg = config("restart_delay = 0");
+        let mut backoff = Backoff::new(0);
+        for _ in 0..3 {
+            assert_eq!(backoff.next_delay(&cfg), Duration::new(0, 0));
+        }
+    }
+
+    #[test]
+    fn test_backoff_jitter() {
+        let cfg = config("restart_delay = 10\nrestart_delay_multiplier = 1.0");
+        for idx in 0..10 {
+            let mut backoff = Backoff::new(idx);
+            for _ in 0..10 {
+                let delay = secs(backoff.next_delay(&cfg));
+                assert!(delay >= 8.0, "delay: {}", delay);
+                assert!(delay <= 12.0, "delay: {}", delay);
+            }
+        }
+    }
+
+    #[test]
+    fn test_backoff_stable_worker() {
+        let cfg = config("restart_jitter = 0.0\nrestart_stable_period = 0");
+        let mut backoff = Backoff::new(0);
+        assert_eq!(backoff.next_delay(&cfg), Duration::new(1, 0));
+        assert_eq!(backoff.next_delay(&cfg), Duration::new(2, 0));
+
+        backoff.loaded();
+        assert_eq!(backoff.next_delay(&cfg), Duration::new(1, 0));
+
+        // worker did not stay loaded for stable period
+        let cfg = config("restart_jitter = 0.0\nrestart_stable_period = 60");
+        backoff.loaded();
+        assert_eq!(backoff.next_delay(&cfg), Duration::new(2, 0));
+    }
+
+    #[test]
+    fn test_start_limit() {
+        let cfg = config("start_limit_burst = 2\nstart_limit_interval = 60");
+        let mut limit = StartLimit::new();
+        assert!(limit.check(&cfg));
+        assert!(limit.check(&cfg));
+        assert!(!limit.check(&cfg));
+
+        limit.reset();
+        assert!(limit.check(&cfg));
+    }
+
+    #[test]
+    fn test_start_limit_disabled() {
+        let cfg = config("start_limit_burst = 0");
+        let mut limit = StartLimit::new();
+        for _ in 0..100 {
+            assert!(limit.check(&cfg));
+        }
+    }
+
+    #[test]
+    fn test_start_limit_interval() {
+        let cfg = config("start_limit_burst = 1\nstart_limit_interval = 0");
+        let mut limit = StartLimit::new();
+        assert!(limit.check(&cfg));
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(limit.check(&cfg));
+    }
+
+    #[test]
+    fn test_restart_policy() {
+        assert!(RestartPolicy::Always.restart(true));
+        assert!(RestartPolicy::Always.restart(false));
+        assert!(!RestartPolicy::OnFailure.restart(true));
+        assert!(RestartPolicy::OnFailure.restart(false));
+        assert!(!RestartPolicy::Never.restart(false));
+        assert!(RestartPolicy::UnlessStopped.restart(true));
+
+        assert!(RestartPolicy::UnlessStopped.keep_stopped());
+        assert!(!RestartPolicy::Always.keep_stopped());
+    }
+}
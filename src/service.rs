@@ -1,16 +1,24 @@
 #![allow(dead_code)]
 
+use nix::sys::signal::{kill, killpg, Signal};
 use nix::unistd::Pid;
 use std;
-use std::time::Duration;
+use std::collections::{HashSet, VecDeque};
+use std::time::{Duration, Instant};
 
 use actix::prelude::*;
 use actix::Response;
-use futures::Future;
+use futures::{future, Future};
 
+use cmd::JournalService;
 use config::ServiceConfig;
-use event::{Event, Reason};
+use event::{Reason, ServiceStatus, State};
+use master_types::LogLine;
+use output::{self, FollowLine, OutputStream};
+use persist::{ServiceJournal, WorkerRecord};
 use process::ProcessError;
+use reaper::{self, Orphan};
+use upgrade::{HandoverState, ServiceHandover};
 use worker::{Worker, WorkerMessage};
 
 /// Service state
@@ -56,6 +64,9 @@ pub enum ServiceOperationError {
     Running,
     Stopped,
     Failed,
+    UnknownRelease,
+    /// Number of workers is out of autoscaling limits
+    ScaleOutOfRange(u16, u16),
 }
 
 #[derive(Clone, Debug)]
@@ -70,38 +81,166 @@ pub enum ReloadStatus {
     Success,
     Failed,
     Stopping,
+    CanaryFailed,
+}
+
+/// Canary worker of the reload
+struct Canary {
+    idx: usize,
+    /// pid of the loaded canary process
+    pid: Option<Pid>,
+    /// soak period timer
+    soak: Option<SpawnHandle>,
+    /// pid of the running health probe
+    probe: Option<Pid>,
+    failed: bool,
 }
 
 pub struct FeService {
     name: String,
+    cfg: ServiceConfig,
     state: ServiceState,
     paused: bool,
+    num: usize,
     workers: Vec<Worker>,
+    retry_after: Option<u32>,
+    retry_max: u16,
+    recovery: Option<SpawnHandle>,
+    recovery_attempts: u16,
+    recovered: Option<Instant>,
+    scaled: Instant,
+    reload_pending: VecDeque<usize>,
+    reload_graceful: bool,
+    reload_pause: Option<SpawnHandle>,
+    canary: Option<Canary>,
+    release: Option<String>,
+    previous: Option<String>,
+    deploying: Option<(Option<String>, Option<String>)>,
+    sessions: HashSet<Pid>,
+    stopped: bool,
+    /// restarts of workers are suspended during master upgrade
+    frozen: bool,
+    cmd: Recipient<JournalService>,
+    journaled: Option<ServiceJournal>,
+    output: output::Output,
 }
 
 impl FeService {
-    pub fn start(num: u16, cfg: ServiceConfig) -> Addr<FeService> {
+    pub fn start(
+        num: u16, release: Option<String>, previous: Option<String>,
+        journal: ServiceJournal, cfg: ServiceConfig, cmd: Recipient<JournalService>,
+    ) -> Addr<FeService> {
         FeService::create(move |ctx| {
-            // create4 workers
-            let mut workers = Vec::new();
-            for idx in 0..num as usize {
-                workers.push(Worker::new(idx, cfg.clone(), ctx.address()));
-            }
+            FeService::new(num, release, previous, journal, cfg, cmd, ctx)
+        })
+    }
 
-            FeService {
-                name: cfg.name.clone(),
-                state: ServiceState::Starting(actix::utils::Condition::default()),
-                paused: false,
-                workers,
+    /// Adopt service handed over by previous master process
+    pub fn adopt(
+        service: ServiceHandover, journal: ServiceJournal, cfg: ServiceConfig,
+        cmd: Recipient<JournalService>,
+    ) -> Addr<FeService> {
+        FeService::create(move |ctx| {
+            let mut srv = FeService::new(
+                service.num as u16,
+                service.release,
+                service.previous,
+                journal,
+                cfg,
+                cmd,
+                ctx,
+            );
+            srv.paused = service.paused;
+            srv.state = match service.state {
+                HandoverState::Running => ServiceState::Running,
+                HandoverState::Failed => ServiceState::Failed,
+                HandoverState::Stopped => ServiceState::Stopped,
+            };
+
+            let worker_cfg = srv.worker_config();
+            for worker in service.workers {
+                while srv.workers.len() <= worker.idx {
+                    let idx = srv.workers.len();
+                    srv.workers.push(Worker::new(
+                        idx,
+                        worker_cfg.clone(),
+                        ctx.address(),
+                    ));
+                }
+                srv.workers[worker.idx].adopt(worker);
             }
+            srv
         })
     }
 
+    fn new(
+        num: u16, release: Option<String>, previous: Option<String>,
+        journal: ServiceJournal, cfg: ServiceConfig, cmd: Recipient<JournalService>,
+        ctx: &mut Context<Self>,
+    ) -> FeService {
+        // number of workers has to be within autoscaling limits
+        let num = match cfg.max_workers {
+            Some(max) => num.max(cfg.min_workers).min(max),
+            None => num,
+        };
+
+        // create4 workers
+        let mut workers = Vec::new();
+        let worker_cfg = cfg.with_release(release.as_ref());
+        for idx in 0..num as usize {
+            workers.push(Worker::new(idx, worker_cfg.clone(), ctx.address()));
+        }
+        for record in &journal.workers {
+            if let Some(worker) = workers.get_mut(record.idx) {
+                worker.set_generation(record.generation);
+            }
+        }
+
+        // explicitly stopped service stays stopped
+        let state = if journal.stopped && cfg.restart.keep_stopped() {
+            info!("Service {:?} is stopped", cfg.name);
+            ServiceState::Stopped
+        } else {
+            ServiceState::Starting(actix::utils::Condition::default())
+        };
+
+        FeService {
+            name: cfg.name.clone(),
+            state,
+            paused: journal.paused,
+            stopped: journal.stopped,
+            num: num as usize,
+            workers,
+            retry_after: cfg.failed_retry_after,
+            retry_max: cfg.failed_retry_max,
+            recovery: None,
+            recovery_attempts: 0,
+            recovered: None,
+            scaled: Instant::now(),
+            reload_pending: VecDeque::new(),
+            reload_graceful: true,
+            reload_pause: None,
+            canary: None,
+            release,
+            previous,
+            deploying: None,
+            sessions: HashSet::new(),
+            frozen: false,
+            cmd,
+            journaled: None,
+            output: output::Output::new(&cfg),
+            cfg,
+        }
+    }
+
     fn check_loading_workers(&mut self, restart_stopped: bool) -> (bool, bool) {
         let mut in_process = false;
         let mut failed = false;
 
-        for worker in &mut self.workers {
+        // worker that exited successfully is stopped, restart policy decides
+        let restart_stopped = restart_stopped && self.cfg.restart.restart(true);
+
+        for worker in self.workers.iter_mut().take(self.num) {
             if worker.is_failed() {
                 failed = true;
             } else if worker.is_stopped() {
@@ -117,8 +256,36 @@ impl FeService {
         (failed, in_process)
     }
 
-    // update internal state
-    fn update(&mut self) {
+    // update internal state and journal it
+    fn update(&mut self, ctx: &mut Context<Self>) {
+        self.update_state(ctx);
+        self.journal();
+    }
+
+    fn update_state(&mut self, ctx: &mut Context<Self>) {
+        // remove stopped workers after scale down
+        while self.workers.len() > self.num {
+            match self.workers.last() {
+                Some(w) if w.is_stopped() || (w.is_failed() && !w.is_running()) => (),
+                _ => break,
+            }
+            if let Some(worker) = self.workers.pop() {
+                info!("Worker {} of {:?} removed", worker.idx, self.name);
+            }
+        }
+
+        // remember worker sessions, orphans are attributed by session,
+        // session is forgotten once all processes of the session exit
+        let workers: HashSet<Pid> = self
+            .workers
+            .iter()
+            .flat_map(|w| w.pids())
+            .filter(|pid| pid.as_raw() > 0)
+            .collect();
+        self.sessions
+            .retain(|sid| workers.contains(sid) || killpg(*sid, None).is_ok());
+        self.sessions.extend(workers);
+
         let state = std::mem::replace(&mut self.state, ServiceState::Failed);
 
         match state {
@@ -140,18 +307,40 @@ impl FeService {
                     }
                 } else {
                     if !in_process {
+                        // service was paused before master restart
+                        if self.paused {
+                            for worker in &mut self.workers {
+                                worker.pause(Reason::None);
+                            }
+                        }
                         task.set(StartStatus::Success);
                         self.state = ServiceState::Running;
+
+                        // workers could exit without restart during startup
+                        return self.update_state(ctx);
                     } else {
                         self.state = ServiceState::Starting(task);
                     }
                 }
             }
             ServiceState::Reloading(task) => {
+                // remaining workers wait for canary
+                if self.canary.is_some() {
+                    if self.check_canary(ctx) {
+                        self.state = ServiceState::Reloading(task);
+                    } else {
+                        self.deploy_finished(false);
+                        task.set(ReloadStatus::CanaryFailed);
+                        self.state = ServiceState::Running;
+                    }
+                    return self.check_recovery(ctx);
+                }
+
                 let (failed, in_process) = self.check_loading_workers(true);
 
                 // if we have failed workers, stop all and change service state to failed
                 if failed {
+                    self.cancel_reload(ctx);
                     if in_process {
                         for worker in &mut self.workers {
                             if !(worker.is_stopped() || worker.is_failed()) {
@@ -160,22 +349,57 @@ impl FeService {
                         }
                         self.state = ServiceState::Reloading(task);
                     } else {
+                        self.deploy_finished(false);
                         task.set(ReloadStatus::Failed);
                         self.state = ServiceState::Failed;
                     }
                 } else {
-                    if !in_process {
+                    if in_process || self.reload_pause.is_some() {
+                        self.state = ServiceState::Reloading(task);
+                    } else if !self.reload_pending.is_empty() {
+                        // previous batch is done, reload next batch
+                        self.state = ServiceState::Reloading(task);
+                        if self.cfg.reload_pause == 0 {
+                            self.reload_batch();
+                            return self.update(ctx);
+                        }
+                        let pause = Duration::new(u64::from(self.cfg.reload_pause), 0);
+                        self.reload_pause = Some(ctx.run_later(pause, |act, ctx| {
+                            act.reload_pause = None;
+                            act.reload_batch();
+                            act.update(ctx);
+                        }));
+                    } else {
+                        self.deploy_finished(true);
                         task.set(ReloadStatus::Success);
                         self.state = ServiceState::Running;
+                    }
+                }
+            }
+            ServiceState::Running => {
+                // all workers exited and restart policy does not restart them
+                let exited = self
+                    .workers
+                    .iter()
+                    .all(|w| w.is_stopped() || (w.is_failed() && !w.is_running()));
+
+                if exited && !self.workers.is_empty() {
+                    if self.workers.iter().any(|w| w.is_failed()) {
+                        info!("Service {:?} failed", self.name);
+                        self.state = ServiceState::Failed;
                     } else {
-                        self.state = ServiceState::Reloading(task);
+                        info!("Service {:?} stopped", self.name);
+                        self.state = ServiceState::Stopped;
                     }
+                } else {
+                    self.state = ServiceState::Running;
                 }
             }
             ServiceState::Stopping(task) => {
                 let (_, in_process) = self.check_loading_workers(false);
 
                 if !in_process {
+                    self.kill_orphans();
                     task.set(());
                     self.state = ServiceState::Stopped;
                 } else {
@@ -184,6 +408,484 @@ impl FeService {
             }
             state => self.state = state,
         }
+
+        self.check_recovery(ctx);
+    }
+
+    // schedule recovery of failed service or failed workers
+    fn check_recovery(&mut self, ctx: &mut Context<Self>) {
+        let failed = match self.state {
+            // service is not recovered if all workers exited by restart policy
+            ServiceState::Failed => {
+                self.workers.iter().any(|w| w.is_recoverable())
+                    || !self.workers.iter().any(|w| w.is_failed())
+            }
+            ServiceState::Running => self.workers.iter().any(|w| w.is_recoverable()),
+            _ => false,
+        };
+        let retry_after = match self.retry_after {
+            Some(retry_after) => Duration::new(u64::from(retry_after), 0),
+            None => return,
+        };
+
+        if !failed {
+            // service is healthy after recovery
+            if self.recovery_attempts > 0 && self.recovered.is_none() {
+                if let ServiceState::Running = self.state {
+                    self.recovered = Some(Instant::now());
+                }
+            }
+            return;
+        }
+        if self.recovery.is_some() {
+            return;
+        }
+
+        // service was healthy long enough, reset recovery attempts
+        if let Some(recovered) = self.recovered.take() {
+            if Instant::now().duration_since(recovered) >= retry_after {
+                self.recovery_attempts = 0;
+            }
+        }
+        if self.recovery_attempts >= self.retry_max {
+            error!(
+                "Service {:?} failed, giving up after {} recovery attempts",
+                self.name, self.recovery_attempts
+            );
+            return;
+        }
+
+        info!(
+            "Service {:?} failed, recovery in {} secs",
+            self.name,
+            retry_after.as_secs()
+        );
+        self.recovery = Some(ctx.run_later(retry_after, |act, ctx| {
+            act.recovery = None;
+            act.recovery_attempts += 1;
+            info!(
+                "Recovering service {:?}, attempt {}",
+                act.name, act.recovery_attempts
+            );
+
+            match act.state {
+                ServiceState::Failed => ctx.notify(Start(Reason::AutoRecovery)),
+                ServiceState::Running => {
+                    for worker in &mut act.workers {
+                        if worker.is_recoverable() {
+                            worker.start(Reason::AutoRecovery);
+                        }
+                    }
+                    act.update(ctx);
+                }
+                _ => (),
+            }
+        }));
+    }
+
+    // cancel scheduled recovery
+    fn cancel_recovery(&mut self, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.recovery.take() {
+            ctx.cancel_future(handle);
+        }
+        self.recovery_attempts = 0;
+        self.recovered = None;
+    }
+
+    // reload next batch of workers
+    //
+    // First `max_unavailable` workers of the batch get stopped before new process
+    // starts, other workers get reloaded and run old and new process side by side.
+    fn reload_batch(&mut self) {
+        let unavailable = self.cfg.max_unavailable as usize;
+        let size = self
+            .cfg
+            .reload_batch_size
+            .map(|size| size as usize + unavailable);
+
+        let mut reloaded = 0;
+        while size.map_or(true, |size| reloaded < size) {
+            let idx = match self.reload_pending.pop_front() {
+                Some(idx) => idx,
+                None => break,
+            };
+            let worker = match self.workers.get_mut(idx) {
+                Some(worker) => worker,
+                None => continue,
+            };
+            // worker is not running, just start it
+            if worker.is_stopped() || (worker.is_failed() && !worker.is_running()) {
+                worker.reload(self.reload_graceful, Reason::ConsoleRequest);
+                continue;
+            }
+            // worker got restarted in the meantime
+            if !worker.is_running() || worker.is_failed() {
+                continue;
+            }
+
+            if reloaded < unavailable {
+                if self.reload_graceful {
+                    worker.stop(Reason::ConsoleRequest);
+                } else {
+                    worker.quit(Reason::ConsoleRequest);
+                }
+                worker.start(Reason::ConsoleRequest);
+            } else {
+                worker.reload(self.reload_graceful, Reason::ConsoleRequest);
+            }
+            reloaded += 1;
+        }
+        if !self.reload_pending.is_empty() {
+            info!(
+                "Reloaded {} workers of {:?}, {} workers pending",
+                reloaded,
+                self.name,
+                self.reload_pending.len()
+            );
+        }
+    }
+
+    // cancel remaining batches of rolling reload
+    fn cancel_reload(&mut self, ctx: &mut Context<Self>) {
+        if let Some(handle) = self.reload_pause.take() {
+            ctx.cancel_future(handle);
+        }
+        self.reload_pending.clear();
+
+        if let Some(canary) = self.canary.take() {
+            if let Some(handle) = canary.soak {
+                ctx.cancel_future(handle);
+            }
+            if let Some(pid) = canary.probe {
+                let _ = kill(pid, Signal::SIGKILL);
+            }
+        }
+    }
+
+    // check canary worker, returns `false` if canary failed
+    fn check_canary(&mut self, ctx: &mut Context<Self>) -> bool {
+        let (idx, pid, failed) = match self.canary {
+            Some(ref canary) => (canary.idx, canary.pid, canary.failed),
+            None => return true,
+        };
+        let (running, worker_failed, worker_pid) = match self.workers.get(idx) {
+            Some(worker) => (worker.is_running(), worker.is_failed(), worker.pid()),
+            None => (false, true, None),
+        };
+        let running = running && !worker_failed;
+
+        // canary must stay loaded, without restarts
+        let failed = failed
+            || match pid {
+                Some(pid) => !running || worker_pid != Some(pid),
+                None => worker_failed,
+            };
+        if failed {
+            error!("Canary worker of {:?} failed, stop reload", self.name);
+            if let Some(worker) = self.workers.get_mut(idx) {
+                worker.event(State::ReloadFailed, Reason::CanaryFailed, None);
+            }
+            self.cancel_reload(ctx);
+            return false;
+        }
+
+        // canary is loaded, start soak period
+        if pid.is_none() && running {
+            let period = Duration::new(u64::from(self.cfg.canary_period), 0);
+            info!(
+                "Canary worker of {:?} loaded, soak period {} secs",
+                self.name,
+                period.as_secs()
+            );
+            let soak = ctx.run_later(period, |act, ctx| {
+                if let Some(ref mut canary) = act.canary {
+                    canary.soak = None;
+                }
+                act.canary_probe(ctx);
+            });
+            if let Some(ref mut canary) = self.canary {
+                canary.pid = worker_pid;
+                canary.soak = Some(soak);
+            }
+        }
+        true
+    }
+
+    // run health probe after soak period
+    fn canary_probe(&mut self, ctx: &mut Context<Self>) {
+        let pid = match self.canary {
+            Some(Canary { pid: Some(pid), .. }) => pid,
+            _ => return,
+        };
+        let cmd = match self.cfg.canary_probe {
+            Some(ref cmd) => cmd.clone(),
+            None => return self.canary_passed(ctx),
+        };
+
+        // probe process gets reaped by command center
+        let probe = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(&cmd)
+            .env("FECTL_CANARY_PID", format!("{}", pid))
+            .spawn();
+
+        match probe {
+            Ok(child) => {
+                let probe = Pid::from_raw(child.id() as i32);
+                reaper::add_helper(probe);
+                if let Some(ref mut canary) = self.canary {
+                    canary.probe = Some(probe);
+                }
+                let timeout = Duration::new(u64::from(self.cfg.canary_probe_timeout), 0);
+                ctx.run_later(timeout, move |act, ctx| {
+                    if let Some(ref mut canary) = act.canary {
+                        if canary.probe == Some(probe) {
+                            error!(
+                                "Canary probe timeout after {} secs",
+                                timeout.as_secs()
+                            );
+                            let _ = kill(probe, Signal::SIGKILL);
+                            canary.probe = None;
+                            canary.failed = true;
+                        }
+                    }
+                    act.update(ctx);
+                });
+            }
+            Err(err) => {
+                error!("Can not run canary probe {:?}: {}", cmd, err);
+                if let Some(ref mut canary) = self.canary {
+                    canary.failed = true;
+                }
+                self.update(ctx);
+            }
+        }
+    }
+
+    // canary passed soak period, reload remaining workers
+    fn canary_passed(&mut self, ctx: &mut Context<Self>) {
+        info!(
+            "Canary worker of {:?} is healthy, continue reload",
+            self.name
+        );
+        self.canary = None;
+        self.update(ctx);
+    }
+
+    // processes reparented to master that belong to this service
+    fn orphans(&self) -> Vec<Orphan> {
+        let workers: HashSet<Pid> = self.workers.iter().flat_map(|w| w.pids()).collect();
+        let all = reaper::orphans(&workers);
+
+        let sessions = &self.sessions;
+        let name = &self.name;
+        all.into_iter()
+            .filter(|o| {
+                sessions.contains(&o.sid)
+                    || sessions.contains(&o.pgid)
+                    || reaper::service_name(o.pid).as_ref() == Some(name)
+            }).collect()
+    }
+
+    // kill orphaned processes of stopped service
+    fn kill_orphans(&mut self) {
+        for orphan in self.orphans() {
+            warn!(
+                "Killing orphaned process {} ({}) of {:?}",
+                orphan.pid, orphan.cmd, self.name
+            );
+            let _ = kill(orphan.pid, Signal::SIGKILL);
+        }
+    }
+
+    // service config with active release
+    fn worker_config(&self) -> ServiceConfig {
+        self.cfg.with_release(self.release.as_ref())
+    }
+
+    // switch workers to new release, workers pick it up on next start
+    fn set_release(&mut self, release: Option<String>, previous: Option<String>) {
+        self.release = release;
+        self.previous = previous;
+
+        let cfg = self.worker_config();
+        for worker in &mut self.workers {
+            worker.set_config(cfg.clone());
+        }
+    }
+
+    // restore previous release if deployment failed
+    fn deploy_finished(&mut self, success: bool) {
+        if let Some((release, previous)) = self.deploying.take() {
+            if success {
+                info!("Service {:?} runs release {:?}", self.name, self.release);
+            } else {
+                error!(
+                    "Deployment of {:?} release {:?} failed, restore release {:?}",
+                    self.name, self.release, release
+                );
+                self.set_release(release, previous);
+            }
+        }
+    }
+
+    // reload workers, in batches and with canary worker if requested
+    fn reload(
+        &mut self, graceful: bool, canary: bool, ctx: &mut Context<Self>,
+    ) -> Response<ReloadStatus, ServiceOperationError> {
+        debug!("Reloading service: {:?}", self.name);
+        let mut task = actix::utils::Condition::default();
+        let rx = task.wait();
+        self.paused = false;
+        self.state = ServiceState::Reloading(task);
+
+        // start workers that are not running, running workers get reloaded in batches
+        self.cancel_reload(ctx);
+        self.reload_graceful = graceful;
+        for (idx, worker) in self.workers.iter_mut().take(self.num).enumerate() {
+            if canary || (worker.is_running() && !worker.is_failed()) {
+                self.reload_pending.push_back(idx);
+            } else {
+                worker.reload(graceful, Reason::ConsoleRequest);
+            }
+        }
+
+        // reload first running worker, other workers wait for soak period
+        let canary = if canary {
+            self.workers
+                .iter()
+                .take(self.num)
+                .position(|w| w.is_running() && !w.is_failed())
+        } else {
+            None
+        };
+        if let Some(idx) = canary {
+            info!("Reloading canary worker {} of {:?}", idx, self.name);
+            self.reload_pending.retain(|i| *i != idx);
+            self.workers[idx].reload(graceful, Reason::ConsoleRequest);
+            self.canary = Some(Canary {
+                idx,
+                pid: None,
+                soak: None,
+                probe: None,
+                failed: false,
+            });
+        } else {
+            self.reload_batch();
+        }
+        self.update(ctx);
+
+        Response::async(rx.map_err(|_| ServiceOperationError::Failed))
+    }
+
+    // change number of workers
+    fn scale(&mut self, num: usize, reason: Reason, ctx: &mut Context<Self>) {
+        let running = match self.state {
+            ServiceState::Running => true,
+            _ => false,
+        };
+        info!(
+            "Scale service {:?} from {} to {} workers",
+            self.name, self.num, num
+        );
+
+        // stop highest-index workers
+        for worker in self.workers.iter_mut().skip(num) {
+            worker.stop(reason.clone());
+        }
+
+        // start new workers, or workers that are going to be stopped
+        for idx in 0..num {
+            if idx >= self.workers.len() {
+                let mut worker = Worker::new(idx, self.worker_config(), ctx.address());
+                if self.frozen {
+                    worker.freeze();
+                }
+                self.workers.push(worker);
+            }
+            if running {
+                self.workers[idx].start(reason.clone());
+            }
+        }
+        self.num = num;
+        self.update(ctx);
+    }
+
+    // adjust number of workers to load reported by workers
+    fn autoscale(&mut self, ctx: &mut Context<Self>) {
+        let max = match self.cfg.max_workers {
+            Some(max) => max as usize,
+            None => return,
+        };
+        match self.state {
+            ServiceState::Running if !self.paused => (),
+            _ => return,
+        }
+        let cooldown = Duration::new(u64::from(self.cfg.scale_cooldown), 0);
+        if Instant::now().duration_since(self.scaled) < cooldown {
+            return;
+        }
+
+        let loads: Vec<f64> = self
+            .workers
+            .iter()
+            .take(self.num)
+            .filter_map(|w| w.load())
+            .collect();
+        if loads.is_empty() {
+            return;
+        }
+        let load = loads.iter().sum::<f64>() / loads.len() as f64;
+
+        // number of workers that brings average load between thresholds
+        let target = (self.cfg.scale_up_threshold + self.cfg.scale_down_threshold) / 2.0;
+        let desired = (load * self.num as f64 / target).ceil() as usize;
+
+        let num = if load > self.cfg.scale_up_threshold {
+            desired.max(self.num + 1)
+        } else if load < self.cfg.scale_down_threshold {
+            desired.min(self.num.saturating_sub(1))
+        } else {
+            return;
+        };
+        let num = num.max(self.cfg.min_workers as usize).min(max);
+
+        if num != self.num {
+            info!(
+                "Autoscale service {:?}, average worker load: {:.2}",
+                self.name, load
+            );
+            self.scaled = Instant::now();
+            self.scale(num, Reason::Autoscale, ctx);
+        }
+    }
+
+    // send runtime state to command center, so it survives master crash
+    fn journal(&mut self) {
+        let journal = ServiceJournal {
+            num: self.num,
+            stopped: self.stopped,
+            paused: self.paused,
+            workers: self
+                .workers
+                .iter()
+                .map(|worker| WorkerRecord {
+                    idx: worker.idx,
+                    generation: worker.generation(),
+                    pids: worker
+                        .pids()
+                        .iter()
+                        .map(|pid| pid.as_raw())
+                        .filter(|pid| *pid > 0)
+                        .collect(),
+                }).collect(),
+        };
+        if self.journaled.as_ref() != Some(&journal) {
+            let _ = self
+                .cmd
+                .do_send(JournalService(self.name.clone(), journal.clone()));
+            self.journaled = Some(journal);
+        }
     }
 
     fn message(&mut self, pid: Pid, message: WorkerMessage) {
@@ -196,10 +898,20 @@ impl FeService {
 impl Actor for FeService {
     type Context = Context<Self>;
 
-    fn started(&mut self, _: &mut Context<Self>) {
-        // start workers
-        for worker in &mut self.workers {
-            worker.start(Reason::Initial);
+    fn started(&mut self, ctx: &mut Context<Self>) {
+        // start workers, adopted service is already running
+        if let ServiceState::Starting(_) = self.state {
+            for worker in &mut self.workers {
+                worker.start(Reason::Initial);
+            }
+        } else {
+            self.check_recovery(ctx);
+        }
+
+        // check workers load periodically
+        if self.cfg.max_workers.is_some() {
+            let interval = Duration::new(u64::from(self.cfg.scale_interval.max(1)), 0);
+            ctx.run_interval(interval, |act, ctx| act.autoscale(ctx));
         }
     }
 }
@@ -210,9 +922,23 @@ pub struct ProcessMessage(pub usize, pub Pid, pub WorkerMessage);
 impl Handler<ProcessMessage> for FeService {
     type Result = ();
 
-    fn handle(&mut self, msg: ProcessMessage, _: &mut Context<Self>) {
-        self.workers[msg.0].message(msg.1, &msg.2);
-        self.update();
+    fn handle(&mut self, msg: ProcessMessage, ctx: &mut Context<Self>) {
+        if let Some(worker) = self.workers.get_mut(msg.0) {
+            worker.message(msg.1, &msg.2);
+        }
+        self.update(ctx);
+    }
+}
+
+/// Line of captured worker output
+#[derive(Message)]
+pub struct ProcessOutput(pub usize, pub Pid, pub OutputStream, pub String);
+
+impl Handler<ProcessOutput> for FeService {
+    type Result = ();
+
+    fn handle(&mut self, msg: ProcessOutput, _: &mut Context<Self>) {
+        self.output.write(msg.0, msg.1, msg.2, &msg.3);
     }
 }
 
@@ -223,11 +949,39 @@ impl Handler<ProcessFailed> for FeService {
     type Result = ();
 
     fn handle(&mut self, msg: ProcessFailed, ctx: &mut Context<Self>) {
-        // TODO: delay failure processing, needs better approach
-        ctx.run_later(Duration::new(5, 0), move |act, _| {
-            act.workers[msg.0].exited(msg.1, &msg.2);
-            act.update();
-        });
+        if let Some(worker) = self.workers.get_mut(msg.0) {
+            worker.exited(msg.1, &msg.2);
+        }
+        self.update(ctx);
+    }
+}
+
+/// Restart delay of the worker is elapsed
+#[derive(Message)]
+pub struct RestartWorker(pub usize, pub usize);
+
+impl Handler<RestartWorker> for FeService {
+    type Result = ();
+
+    fn handle(&mut self, msg: RestartWorker, ctx: &mut Context<Self>) {
+        if let Some(worker) = self.workers.get_mut(msg.0) {
+            worker.restart_delayed(msg.1);
+        }
+        self.update(ctx);
+    }
+}
+
+/// Signal of the stop sequence is sent to worker process
+#[derive(Message)]
+pub struct ProcessSignaled(pub usize, pub Pid, pub Signal);
+
+impl Handler<ProcessSignaled> for FeService {
+    type Result = ();
+
+    fn handle(&mut self, msg: ProcessSignaled, _: &mut Context<Self>) {
+        if let Some(worker) = self.workers.get_mut(msg.0) {
+            worker.signaled(msg.1, msg.2);
+        }
     }
 }
 
@@ -237,9 +991,11 @@ pub struct ProcessLoaded(pub usize, pub Pid);
 impl Handler<ProcessLoaded> for FeService {
     type Result = ();
 
-    fn handle(&mut self, msg: ProcessLoaded, _: &mut Context<Self>) {
-        self.workers[msg.0].loaded(msg.1);
-        self.update();
+    fn handle(&mut self, msg: ProcessLoaded, ctx: &mut Context<Self>) {
+        if let Some(worker) = self.workers.get_mut(msg.0) {
+            worker.loaded(msg.1);
+        }
+        self.update(ctx);
     }
 }
 
@@ -249,11 +1005,30 @@ pub struct ProcessExited(pub Pid, pub ProcessError);
 impl Handler<ProcessExited> for FeService {
     type Result = ();
 
-    fn handle(&mut self, msg: ProcessExited, _: &mut Context<Self>) {
+    fn handle(&mut self, msg: ProcessExited, ctx: &mut Context<Self>) {
+        // canary health probe exited
+        let probe = match self.canary {
+            Some(ref mut canary) if canary.probe == Some(msg.0) => {
+                canary.probe = None;
+                match msg.1 {
+                    ProcessError::ExitCode(0) => true,
+                    ref err => {
+                        error!("Canary probe failed: {:?}", err);
+                        canary.failed = true;
+                        false
+                    }
+                }
+            }
+            _ => false,
+        };
+        if probe {
+            return self.canary_passed(ctx);
+        }
+
         for worker in &mut self.workers {
             worker.exited(msg.0, &msg.1);
         }
-        self.update();
+        self.update(ctx);
     }
 }
 
@@ -278,18 +1053,53 @@ impl Handler<Pids> for FeService {
     }
 }
 
-/// Service status command
-pub struct Status;
+/// Reopen output file and ask workers to reopen their log files
+#[derive(Message)]
+pub struct ReopenLogs;
+
+impl Handler<ReopenLogs> for FeService {
+    type Result = ();
+
+    fn handle(&mut self, _: ReopenLogs, _: &mut Context<Self>) {
+        self.output.reopen();
+        for worker in &self.workers {
+            worker.reopen_logs();
+        }
+    }
+}
+
+/// Captured output stored in service buffer, new lines are sent
+/// to recipient if it is set
+pub struct Logs(pub Option<usize>, pub Option<usize>, pub Option<Recipient<FollowLine>>);
+
+impl Message for Logs {
+    type Result = Vec<LogLine>;
+}
+
+impl Handler<Logs> for FeService {
+    type Result = MessageResult<Logs>;
+
+    fn handle(&mut self, msg: Logs, _: &mut Context<Self>) -> Self::Result {
+        let lines = self.output.tail(msg.0, msg.1);
+        if let Some(rcp) = msg.2 {
+            self.output.follow(msg.0, rcp);
+        }
+        MessageResult(lines)
+    }
+}
+
+/// Service status command, orphaned processes are reported if flag is set
+pub struct Status(pub bool);
 
 impl Message for Status {
-    type Result = Result<(String, Vec<(String, Vec<Event>)>), ()>;
+    type Result = Result<ServiceStatus, ()>;
 }
 
 impl Handler<Status> for FeService {
-    type Result = Result<(String, Vec<(String, Vec<Event>)>), ()>;
+    type Result = Result<ServiceStatus, ()>;
 
-    fn handle(&mut self, _: Status, _: &mut Context<Self>) -> Self::Result {
-        let mut events: Vec<(String, Vec<Event>)> = Vec::new();
+    fn handle(&mut self, msg: Status, _: &mut Context<Self>) -> Self::Result {
+        let mut events = Vec::new();
         for worker in &self.workers {
             events.push((
                 format!("worker({})", worker.idx + 1),
@@ -305,12 +1115,84 @@ impl Handler<Status> for FeService {
             },
             _ => self.state.description(),
         };
-        Ok((status.to_owned(), events))
+        // scan of process table is expensive, only for explicit status command
+        let orphans = if msg.0 {
+            self.orphans()
+                .iter()
+                .map(|o| format!("{} ({})", o.pid, o.cmd))
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Ok(ServiceStatus {
+            status: status.to_owned(),
+            workers: self.num,
+            release: self.release.clone(),
+            orphans,
+            events,
+        })
+    }
+}
+
+/// Service state for upgraded master process
+pub struct Handover;
+
+impl Message for Handover {
+    type Result = Result<ServiceHandover, ServiceOperationError>;
+}
+
+impl Handler<Handover> for FeService {
+    type Result = Response<ServiceHandover, ServiceOperationError>;
+
+    fn handle(&mut self, _: Handover, _: &mut Context<Self>) -> Self::Result {
+        let state = match self.state {
+            ServiceState::Running => HandoverState::Running,
+            ServiceState::Failed => HandoverState::Failed,
+            ServiceState::Stopped => HandoverState::Stopped,
+            _ => return Response::reply(Err(self.state.error())),
+        };
+
+        let workers: Vec<_> = self.workers.iter().map(|w| w.handover()).collect();
+        let (paused, num) = (self.paused, self.num);
+        let (release, previous) = (self.release.clone(), self.previous.clone());
+        Response::async(
+            future::join_all(workers)
+                .map(move |workers| ServiceHandover {
+                    state,
+                    paused,
+                    num,
+                    release,
+                    previous,
+                    workers,
+                }).map_err(|_| ServiceOperationError::Failed),
+        )
+    }
+}
+
+/// Suspend or resume restarts of workers during master upgrade
+#[derive(Message)]
+pub struct Freeze(pub bool);
+
+impl Handler<Freeze> for FeService {
+    type Result = ();
+
+    fn handle(&mut self, msg: Freeze, ctx: &mut Context<Self>) {
+        self.frozen = msg.0;
+        for worker in &mut self.workers {
+            if msg.0 {
+                worker.freeze();
+            } else {
+                worker.unfreeze();
+            }
+        }
+        if !msg.0 {
+            self.update(ctx);
+        }
     }
 }
 
 /// Start service command
-pub struct Start;
+pub struct Start(pub Reason);
 
 impl Message for Start {
     type Result = Result<StartStatus, ServiceOperationError>;
@@ -319,20 +1201,26 @@ impl Message for Start {
 impl Handler<Start> for FeService {
     type Result = Response<StartStatus, ServiceOperationError>;
 
-    fn handle(&mut self, _: Start, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Start, ctx: &mut Context<Self>) -> Self::Result {
         match self.state {
             ServiceState::Starting(ref mut task) => {
                 Response::async(task.wait().map_err(|_| ServiceOperationError::Failed))
             }
             ServiceState::Failed | ServiceState::Stopped => {
                 debug!("Starting service: {:?}", self.name);
+                match msg.0 {
+                    Reason::AutoRecovery => (),
+                    _ => self.cancel_recovery(ctx),
+                }
                 let mut task = actix::utils::Condition::default();
                 let rx = task.wait();
                 self.paused = false;
+                self.stopped = false;
                 self.state = ServiceState::Starting(task);
-                for worker in &mut self.workers {
-                    worker.start(Reason::ConsoleRequest);
+                for worker in self.workers.iter_mut().take(self.num) {
+                    worker.start(msg.0.clone());
                 }
+                self.journal();
                 Response::async(rx.map_err(|_| ServiceOperationError::Failed))
             }
             _ => Response::reply(Err(self.state.error())),
@@ -358,6 +1246,7 @@ impl Handler<Pause> for FeService {
                     worker.pause(Reason::ConsoleRequest);
                 }
                 self.paused = true;
+                self.journal();
                 Ok(())
             }
             _ => Err(self.state.error()),
@@ -383,6 +1272,7 @@ impl Handler<Resume> for FeService {
                     worker.resume(Reason::ConsoleRequest);
                 }
                 self.paused = false;
+                self.journal();
                 Ok(())
             }
             _ => Err(self.state.error()),
@@ -391,7 +1281,7 @@ impl Handler<Resume> for FeService {
 }
 
 /// Reload service
-pub struct Reload(pub bool);
+pub struct Reload(pub bool, pub bool);
 
 impl Message for Reload {
     type Result = Result<ReloadStatus, ServiceOperationError>;
@@ -400,24 +1290,84 @@ impl Message for Reload {
 impl Handler<Reload> for FeService {
     type Result = Response<ReloadStatus, ServiceOperationError>;
 
-    fn handle(&mut self, msg: Reload, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Reload, ctx: &mut Context<Self>) -> Self::Result {
         match self.state {
             ServiceState::Reloading(ref mut task) => {
-                Response::async(task.wait().map_err(|_| ServiceOperationError::Failed))
+                return Response::async(
+                    task.wait().map_err(|_| ServiceOperationError::Failed),
+                )
             }
-            ServiceState::Running | ServiceState::Failed | ServiceState::Stopped => {
-                debug!("Reloading service: {:?}", self.name);
-                let mut task = actix::utils::Condition::default();
-                let rx = task.wait();
-                self.paused = false;
-                self.state = ServiceState::Reloading(task);
-                for worker in &mut self.workers {
-                    worker.reload(msg.0, Reason::ConsoleRequest);
+            ServiceState::Running | ServiceState::Failed | ServiceState::Stopped => (),
+            _ => return Response::reply(Err(self.state.error())),
+        }
+        self.reload(msg.0, msg.1, ctx)
+    }
+}
+
+/// Deploy release, `None` rolls back to previous release
+pub struct Deploy(pub Option<String>, pub bool);
+
+impl Message for Deploy {
+    type Result = Result<ReloadStatus, ServiceOperationError>;
+}
+
+impl Handler<Deploy> for FeService {
+    type Result = Response<ReloadStatus, ServiceOperationError>;
+
+    fn handle(&mut self, msg: Deploy, ctx: &mut Context<Self>) -> Self::Result {
+        match self.state {
+            ServiceState::Running | ServiceState::Failed | ServiceState::Stopped => (),
+            _ => return Response::reply(Err(self.state.error())),
+        }
+
+        let release = match msg.0 {
+            Some(release) => release,
+            None => match self.previous {
+                Some(ref previous) => previous.clone(),
+                None => {
+                    return Response::reply(Err(ServiceOperationError::UnknownRelease))
                 }
-                Response::async(rx.map_err(|_| ServiceOperationError::Failed))
+            },
+        };
+        if !self.cfg.release.contains_key(&release) {
+            return Response::reply(Err(ServiceOperationError::UnknownRelease));
+        }
+        info!("Deploying release {:?} of {:?}", release, self.name);
+
+        let (current, previous) = (self.release.clone(), self.previous.clone());
+        self.deploying = Some((current.clone(), previous));
+        self.set_release(Some(release), current);
+        self.reload(true, msg.1, ctx)
+    }
+}
+
+/// Change number of workers
+pub struct Scale(pub u16);
+
+impl Message for Scale {
+    type Result = Result<(), ServiceOperationError>;
+}
+
+impl Handler<Scale> for FeService {
+    type Result = Result<(), ServiceOperationError>;
+
+    fn handle(&mut self, msg: Scale, ctx: &mut Context<Self>) -> Self::Result {
+        match self.state {
+            ServiceState::Running | ServiceState::Failed | ServiceState::Stopped => (),
+            _ => return Err(self.state.error()),
+        };
+        // number of workers has to be within autoscaling limits
+        if let Some(max) = self.cfg.max_workers {
+            if msg.0 < self.cfg.min_workers || msg.0 > max {
+                return Err(ServiceOperationError::ScaleOutOfRange(
+                    self.cfg.min_workers,
+                    max,
+                ));
             }
-            _ => Response::reply(Err(self.state.error())),
         }
+        self.scaled = Instant::now();
+        self.scale(msg.0 as usize, Reason::ConsoleRequest, ctx);
+        Ok(())
     }
 }
 
@@ -431,12 +1381,19 @@ impl Message for Stop {
 impl Handler<Stop> for FeService {
     type Result = Response<(), ()>;
 
-    fn handle(&mut self, msg: Stop, _: &mut Context<Self>) -> Self::Result {
+    fn handle(&mut self, msg: Stop, ctx: &mut Context<Self>) -> Self::Result {
         let state = std::mem::replace(&mut self.state, ServiceState::Stopped);
+        self.cancel_recovery(ctx);
+        self.cancel_reload(ctx);
+        self.deploy_finished(false);
 
         match state {
             ServiceState::Failed | ServiceState::Stopped => {
                 self.state = state;
+                if let Reason::ConsoleRequest = msg.1 {
+                    self.stopped = true;
+                    self.journal();
+                }
                 return Response::reply(Err(()));
             }
             ServiceState::Stopping(mut task) => {
@@ -453,10 +1410,17 @@ impl Handler<Stop> for FeService {
             ServiceState::Running => (),
         }
 
-        // stop workers
+        // stop workers, paused state is restored after master restart
         let mut task = actix::utils::Condition::default();
         let rx = task.wait();
-        self.paused = false;
+        match msg.1 {
+            Reason::Exit => (),
+            Reason::ConsoleRequest => {
+                self.paused = false;
+                self.stopped = true;
+            }
+            _ => self.paused = false,
+        }
         self.state = ServiceState::Stopping(task);
         for worker in &mut self.workers {
             if msg.0 {
@@ -465,7 +1429,7 @@ impl Handler<Stop> for FeService {
                 worker.quit(msg.1.clone());
             }
         }
-        self.update();
+        self.update(ctx);
 
         Response::async(rx.map(|_| ()).map_err(|_| ()))
     }
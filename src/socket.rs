@@ -2,7 +2,7 @@ use std;
 use std::error::Error;
 use std::io;
 use std::net::TcpListener;
-use std::os::unix::io::AsRawFd;
+use std::os::unix::io::{AsRawFd, FromRawFd, RawFd};
 
 use net2::unix::UnixTcpBuilderExt;
 use net2::TcpBuilder;
@@ -11,6 +11,7 @@ use serde_json as json;
 
 use addrinfo;
 use config::{Proto, SocketConfig};
+use upgrade;
 
 pub struct Socket {
     pub name: String,
@@ -57,10 +58,54 @@ impl Socket {
         }
     }
 
+    // listener inherited from previous master process during upgrade
+    fn inherited(cfg: &SocketConfig) -> Option<Socket> {
+        if !upgrade::is_upgrade() {
+            return None;
+        }
+        let var = std::env::var(format!("FECTL_FD_{}", cfg.name)).ok()?;
+        let mut parts = var.split(',');
+        let fd: RawFd = parts.next()?.parse().ok()?;
+
+        let (mut family, mut socktype, mut protocol) = (0, 0, 0);
+        for part in parts {
+            let mut kv = part.splitn(2, ':');
+            match (kv.next(), kv.next().and_then(|v| v.parse().ok())) {
+                (Some("FAMILY"), Some(val)) => family = val,
+                (Some("SOCKETTYPE"), Some(val)) => socktype = val,
+                (Some("PROTO"), Some(val)) => protocol = val,
+                _ => (),
+            }
+        }
+
+        // port could be changed in config
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let addr = listener.local_addr().ok()?;
+        if cfg.port != 0 && u32::from(addr.port()) != cfg.port {
+            return None;
+        }
+        info!("Inherit listener on {:?}", addr);
+
+        let info = addrinfo::AddrInfo {
+            flags: 0,
+            family: addrinfo::Family::from_int(family),
+            socktype: addrinfo::SocketType::from_int(socktype),
+            protocol: addrinfo::Protocol::from_int(protocol),
+            sockaddr: addr,
+            canonname: None,
+        };
+        Some(Socket::new(cfg.name.clone(), listener, info, cfg))
+    }
+
     pub fn load_config(cfg: &[SocketConfig]) -> Result<Vec<Socket>, std::io::Error> {
         let mut services = Vec::new();
 
         for sock in cfg.iter() {
+            if let Some(socket) = Socket::inherited(sock) {
+                services.push(socket);
+                continue;
+            }
+
             // resolve addresses
             let lookup = addrinfo::lookup_addrinfo(
                 sock.host.clone(),
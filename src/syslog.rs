@@ -0,0 +1,449 @@
+// Syslog logging backend, RFC 5424 messages
+use std::fmt;
+use std::io::{self, Write};
+use std::net::{TcpStream, ToSocketAddrs, UdpSocket};
+use std::os::unix::net::UnixDatagram;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use log::{Level, Log, Metadata, Record};
+use nix::unistd::{gethostname, getpid};
+use time;
+
+/// Default syslog socket
+pub const DEFAULT_ADDRESS: &str = "/dev/log";
+
+/// Timeout of tcp connect and send, logging blocks while it waits for send
+const TIMEOUT: Duration = Duration::from_secs(1);
+
+/// Delay before first reconnect attempt, delay doubles with each failed attempt
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const RECONNECT_DELAY_MAX: Duration = Duration::from_secs(60);
+
+/// Parse facility name into facility code
+pub fn facility(name: &str) -> Option<u8> {
+    let code = match name.trim().to_lowercase().as_str() {
+        "kern" => 0,
+        "user" => 1,
+        "mail" => 2,
+        "daemon" => 3,
+        "auth" => 4,
+        "syslog" => 5,
+        "lpr" => 6,
+        "news" => 7,
+        "uucp" => 8,
+        "cron" => 9,
+        "authpriv" => 10,
+        "ftp" => 11,
+        "local0" => 16,
+        "local1" => 17,
+        "local2" => 18,
+        "local3" => 19,
+        "local4" => 20,
+        "local5" => 21,
+        "local6" => 22,
+        "local7" => 23,
+        _ => return None,
+    };
+    Some(code)
+}
+
+/// Syslog severity of log level
+pub fn severity(level: Level) -> u8 {
+    match level {
+        Level::Error => 3,
+        Level::Warn => 4,
+        Level::Info => 6,
+        Level::Debug | Level::Trace => 7,
+    }
+}
+
+/// Syslog server address, unix socket path, `udp://host:port`
+/// or `tcp://host:port`
+#[derive(Clone, Debug, PartialEq)]
+enum Address {
+    Unix(String),
+    Udp(String),
+    Tcp(String),
+}
+
+impl Address {
+    fn parse(addr: &str) -> Address {
+        if addr.starts_with("udp://") {
+            Address::Udp(addr["udp://".len()..].to_owned())
+        } else if addr.starts_with("tcp://") {
+            Address::Tcp(addr["tcp://".len()..].to_owned())
+        } else {
+            Address::Unix(addr.to_owned())
+        }
+    }
+}
+
+enum Transport {
+    Unix(UnixDatagram),
+    Udp(UdpSocket),
+    Tcp(TcpStream),
+}
+
+impl Transport {
+    fn connect(addr: &Address) -> Result<Transport, io::Error> {
+        match *addr {
+            Address::Unix(ref path) => {
+                let sock = UnixDatagram::unbound()?;
+                sock.connect(path)?;
+                Ok(Transport::Unix(sock))
+            }
+            Address::Udp(ref addr) => {
+                let sock = UdpSocket::bind("0.0.0.0:0")?;
+                sock.connect(addr.as_str())?;
+                Ok(Transport::Udp(sock))
+            }
+            Address::Tcp(ref addr) => {
+                let mut last_err = None;
+                for addr in addr.as_str().to_socket_addrs()? {
+                    match TcpStream::connect_timeout(&addr, TIMEOUT) {
+                        Ok(stream) => {
+                            stream.set_write_timeout(Some(TIMEOUT))?;
+                            return Ok(Transport::Tcp(stream));
+                        }
+                        Err(err) => last_err = Some(err),
+                    }
+                }
+                Err(last_err.unwrap_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "Can not resolve address",
+                    )
+                }))
+            }
+        }
+    }
+
+    fn send(&mut self, msg: &[u8]) -> Result<(), io::Error> {
+        match *self {
+            Transport::Unix(ref sock) => sock.send(msg).map(|_| ()),
+            Transport::Udp(ref sock) => sock.send(msg).map(|_| ()),
+            // octet counting framing, RFC 6587
+            Transport::Tcp(ref mut stream) => {
+                stream.write_all(format!("{} ", msg.len()).as_ref())?;
+                stream.write_all(msg)
+            }
+        }
+    }
+}
+
+struct Connection {
+    transport: Option<Transport>,
+    /// reconnect thread is running
+    connecting: bool,
+    /// next reconnect attempt is not made before this time
+    retry: Instant,
+    delay: Duration,
+    /// records dropped while disconnected
+    dropped: usize,
+}
+
+impl Connection {
+    // backoff is reset only once record is sent, server could accept
+    // connections and close them right away
+    fn connected(&mut self, res: Result<Transport, io::Error>) {
+        self.connecting = false;
+        self.transport = res.ok();
+        self.retry = Instant::now() + self.delay;
+        self.delay = std::cmp::min(self.delay * 2, RECONNECT_DELAY_MAX);
+    }
+
+    fn sent(&mut self) {
+        if self.delay != RECONNECT_DELAY {
+            self.delay = RECONNECT_DELAY;
+            self.retry = Instant::now();
+        }
+    }
+}
+
+/// Logger that sends records to syslog server
+///
+/// Records are dropped while server is not available. Reconnect runs
+/// in separate thread, attempts are delayed with exponential backoff.
+pub struct Syslog {
+    name: String,
+    hostname: String,
+    facility: u8,
+    target: &'static str,
+    address: Address,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl Syslog {
+    /// Connect to syslog server, `name` is used as app name
+    pub fn new(
+        name: &str, facility: u8, address: &str, target: &'static str,
+    ) -> Result<Syslog, io::Error> {
+        let address = Address::parse(address);
+        let transport = Transport::connect(&address)?;
+
+        let mut buf = [0u8; 256];
+        let hostname = match gethostname(&mut buf) {
+            Ok(name) => name.to_string_lossy().into_owned(),
+            Err(_) => "-".to_owned(),
+        };
+
+        Ok(Syslog {
+            name: name.to_owned(),
+            hostname,
+            facility,
+            target,
+            address,
+            conn: Arc::new(Mutex::new(Connection {
+                transport: Some(transport),
+                connecting: false,
+                retry: Instant::now(),
+                delay: RECONNECT_DELAY,
+                dropped: 0,
+            })),
+        })
+    }
+
+    fn format<T: fmt::Display>(&self, level: Level, msg: T) -> String {
+        let t = time::now_utc();
+        // pid is not cached, master process forks during daemonization
+        format!(
+            "<{}>1 {}.{:06}Z {} {} {} - - {}",
+            self.facility * 8 + severity(level),
+            time::strftime("%Y-%m-%dT%H:%M:%S", &t).unwrap(),
+            t.tm_nsec / 1000,
+            self.hostname,
+            self.name,
+            getpid(),
+            msg
+        )
+    }
+
+    // start reconnect thread, unless reconnect attempt is delayed
+    fn reconnect(&self, conn: &mut Connection) {
+        if conn.connecting || Instant::now() < conn.retry {
+            return;
+        }
+        conn.connecting = true;
+
+        let address = self.address.clone();
+        let shared = Arc::clone(&self.conn);
+        let res = thread::Builder::new()
+            .name("syslog".to_owned())
+            .spawn(move || {
+                let res = Transport::connect(&address);
+                if let Ok(mut conn) = shared.lock() {
+                    conn.connected(res);
+                }
+            });
+        if let Err(err) = res {
+            conn.connected(Err(err));
+        }
+    }
+
+    fn send(&self, conn: &mut Connection, msg: &str) -> bool {
+        let res = match conn.transport {
+            Some(ref mut transport) => transport.send(msg.as_ref()),
+            None => return false,
+        };
+        match res {
+            Ok(_) => conn.sent(),
+            Err(_) => conn.transport = None,
+        }
+        res.is_ok()
+    }
+}
+
+impl Log for Syslog {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.target().starts_with(self.target)
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let msg = self.format(record.level(), record.args());
+
+        let mut conn = match self.conn.lock() {
+            Ok(conn) => conn,
+            Err(_) => return,
+        };
+
+        if conn.transport.is_some() && conn.dropped > 0 {
+            let notice = format!(
+                "{} log records dropped, syslog server was not available",
+                conn.dropped
+            );
+            if self.send(&mut conn, &self.format(Level::Warn, notice)) {
+                conn.dropped = 0;
+            }
+        }
+
+        // syslog server could be restarted, reconnect right away
+        // unless previous attempts failed
+        if !self.send(&mut conn, &msg) {
+            conn.dropped += 1;
+            self.reconnect(&mut conn);
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std;
+    use std::io::Read;
+    use std::net::TcpListener;
+
+    fn record(syslog: &Syslog, msg: &str) {
+        syslog.log(
+            &Record::builder()
+                .args(format_args!("{}", msg))
+                .level(Level::Warn)
+                .target("fectl::test")
+                .build(),
+        );
+    }
+
+    fn bind(name: &str) -> (std::path::PathBuf, UnixDatagram) {
+        let path = std::env::temp_dir().join(format!(
+            "fectl-syslog-{}-{}.sock",
+            name,
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&path);
+        let server = UnixDatagram::bind(&path).unwrap();
+        (path, server)
+    }
+
+    #[test]
+    fn test_facility() {
+        assert_eq!(facility("kern"), Some(0));
+        assert_eq!(facility(" Daemon "), Some(3));
+        assert_eq!(facility("local7"), Some(23));
+        assert_eq!(facility("local8"), None);
+        assert_eq!(facility(""), None);
+    }
+
+    #[test]
+    fn test_address_parse() {
+        assert_eq!(
+            Address::parse("/dev/log"),
+            Address::Unix("/dev/log".to_owned())
+        );
+        assert_eq!(
+            Address::parse("udp://127.0.0.1:514"),
+            Address::Udp("127.0.0.1:514".to_owned())
+        );
+        assert_eq!(
+            Address::parse("tcp://logs:601"),
+            Address::Tcp("logs:601".to_owned())
+        );
+    }
+
+    #[test]
+    fn test_format() {
+        let (path, _server) = bind("format");
+        let syslog = Syslog::new("app", 3, path.to_str().unwrap(), "fectl").unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let msg = syslog.format(Level::Warn, "first second");
+        let parts: Vec<_> = msg.splitn(8, ' ').collect();
+        assert_eq!(parts[0], "<28>1");
+        assert_eq!(parts[1].len(), "2018-01-01T00:00:00.000000Z".len());
+        assert!(parts[1].ends_with('Z'));
+        assert_eq!(parts[2], syslog.hostname);
+        assert_eq!(parts[3], "app");
+        assert_eq!(parts[4], format!("{}", getpid()));
+        assert_eq!(&parts[5..], &["-", "-", "first second"]);
+    }
+
+    #[test]
+    fn test_datagram() {
+        let (path, server) = bind("datagram");
+        let syslog = Syslog::new("app", 1, path.to_str().unwrap(), "fectl").unwrap();
+
+        record(&syslog, "hello");
+        // records of other targets are ignored
+        syslog.log(
+            &Record::builder()
+                .args(format_args!("skip"))
+                .target("other")
+                .build(),
+        );
+
+        let mut buf = [0u8; 1024];
+        let size = server.recv(&mut buf).unwrap();
+        let msg = String::from_utf8_lossy(&buf[..size]).into_owned();
+        assert!(msg.starts_with("<12>1 "));
+        assert!(msg.ends_with(&format!(" app {} - - hello", getpid())));
+        server.set_nonblocking(true).unwrap();
+        assert!(server.recv(&mut buf).is_err());
+
+        // records are dropped while server is not available
+        drop(server);
+        let _ = std::fs::remove_file(&path);
+        record(&syslog, "dropped");
+        while syslog.conn.lock().unwrap().connecting {
+            thread::sleep(Duration::from_millis(10));
+        }
+        let server = UnixDatagram::bind(&path).unwrap();
+        server
+            .set_read_timeout(Some(Duration::from_millis(100)))
+            .unwrap();
+
+        // reconnect is delayed, notice is sent before next record
+        let mut received = Vec::new();
+        for _ in 0..50 {
+            record(&syslog, "again");
+            while let Ok(size) = server.recv(&mut buf) {
+                received.push(String::from_utf8_lossy(&buf[..size]).into_owned());
+            }
+            if !received.is_empty() {
+                break;
+            }
+        }
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(received.len(), 2);
+        let notice = received[0].rsplit(" - - ").next().unwrap();
+        let (count, text) = notice.split_at(notice.find(' ').unwrap());
+        assert!(count.parse::<usize>().unwrap() >= 2);
+        assert_eq!(
+            text,
+            " log records dropped, syslog server was not available"
+        );
+        assert!(received[1].ends_with(" - - again"));
+    }
+
+    #[test]
+    fn test_tcp_framing() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = format!("tcp://{}", listener.local_addr().unwrap());
+        let syslog = Syslog::new("app", 1, &addr, "fectl").unwrap();
+        let (mut stream, _) = listener.accept().unwrap();
+
+        record(&syslog, "one");
+        record(&syslog, "two two");
+        drop(syslog);
+
+        let mut data = String::new();
+        stream.read_to_string(&mut data).unwrap();
+
+        // octet counting, `<len> <msg>` without separators
+        let mut msgs = Vec::new();
+        let mut rest = data.as_str();
+        while !rest.is_empty() {
+            let pos = rest.find(' ').unwrap();
+            let len: usize = rest[..pos].parse().unwrap();
+            msgs.push(&rest[pos + 1..pos + 1 + len]);
+            rest = &rest[pos + 1 + len..];
+        }
+        assert_eq!(msgs.len(), 2);
+        assert!(msgs[0].ends_with(" - - one"));
+        assert!(msgs[1].ends_with(" - - two two"));
+    }
+}
@@ -0,0 +1,187 @@
+// Master binary upgrade, new binary replaces master process image
+use std;
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr, OsString};
+use std::io::{self, Read, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::RawFd;
+
+use nix::fcntl::{fcntl, FcntlArg, FdFlag};
+use nix::unistd::execv;
+use serde_json as json;
+
+use event::Event;
+
+/// Environment variable with path to the upgrade state file
+pub const UPGRADE_VAR: &str = "FECTL_UPGRADE";
+
+/// Master state handed over to new master binary
+#[derive(Serialize, Deserialize, Debug)]
+pub struct UpgradeState {
+    /// Master commands socket
+    pub sock: RawFd,
+    /// Lock file, lock is held during upgrade
+    #[serde(default)]
+    pub lock: Option<RawFd>,
+    pub services: HashMap<String, ServiceHandover>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub enum HandoverState {
+    Running,
+    Failed,
+    Stopped,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceHandover {
+    pub state: HandoverState,
+    pub paused: bool,
+    pub num: usize,
+    pub release: Option<String>,
+    pub previous: Option<String>,
+    pub workers: Vec<WorkerHandover>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct WorkerHandover {
+    pub idx: usize,
+    /// Running worker process
+    pub process: Option<ProcessHandover>,
+    pub failed: bool,
+    /// Worker has to be started or reloaded by new master process
+    #[serde(default)]
+    pub restart: bool,
+    /// Processes that new master process has to kill
+    #[serde(default)]
+    pub terminate: Vec<i32>,
+    pub events: Vec<Event>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProcessHandover {
+    pub pid: i32,
+    /// Pipe for reading worker messages
+    pub read: RawFd,
+    /// Pipe for writing worker commands
+    pub write: RawFd,
+    /// Pipes of captured stdout and stderr
+    #[serde(default)]
+    pub output: Option<(RawFd, RawFd)>,
+}
+
+/// Check if master process is started by upgrade
+pub fn is_upgrade() -> bool {
+    std::env::var_os(UPGRADE_VAR).is_some()
+}
+
+/// Path of the upgrade state file
+pub fn state_path(state: &OsStr) -> OsString {
+    let mut path = OsString::from(state);
+    path.push(".upgrade");
+    path
+}
+
+/// Set or clear close-on-exec flag of file descriptor
+pub fn set_cloexec(fd: RawFd, cloexec: bool) {
+    if let Ok(flags) = fcntl(fd, FcntlArg::F_GETFD) {
+        let mut flags = FdFlag::from_bits_truncate(flags);
+        flags.set(FdFlag::FD_CLOEXEC, cloexec);
+        let _ = fcntl(fd, FcntlArg::F_SETFD(flags));
+    }
+}
+
+impl UpgradeState {
+    /// Load state handed over by previous master process
+    pub fn load() -> Option<UpgradeState> {
+        let path = std::env::var_os(UPGRADE_VAR)?;
+        std::env::remove_var(UPGRADE_VAR);
+
+        let mut buf = Vec::new();
+        let res = std::fs::File::open(&path).and_then(|mut f| f.read_to_end(&mut buf));
+        let _ = std::fs::remove_file(&path);
+        if let Err(err) = res {
+            error!("Can not read upgrade state {:?}: {}", path, err);
+            return None;
+        }
+        match json::from_slice(&buf) {
+            Ok(state) => Some(state),
+            Err(err) => {
+                error!("Can not parse upgrade state {:?}: {}", path, err);
+                None
+            }
+        }
+    }
+
+    pub fn save(&self, path: &OsStr) -> Result<(), io::Error> {
+        let mut file = std::fs::File::create(path)?;
+        file.write_all(json::to_string(self)?.as_ref())?;
+        file.sync_all()
+    }
+
+    /// File descriptors that new master process inherits
+    pub fn fds(&self) -> Vec<RawFd> {
+        let mut fds = vec![self.sock];
+        fds.extend(self.lock);
+        for srv in self.services.values() {
+            for worker in &srv.workers {
+                if let Some(ref process) = worker.process {
+                    fds.push(process.read);
+                    fds.push(process.write);
+                    if let Some((stdout, stderr)) = process.output {
+                        fds.push(stdout);
+                        fds.push(stderr);
+                    }
+                }
+            }
+        }
+        fds
+    }
+
+    /// Execute master binary, returns only if execution fails
+    pub fn exec(&self, path: &OsStr) -> io::Error {
+        let fds = self.fds();
+        for fd in &fds {
+            set_cloexec(*fd, false);
+        }
+
+        let err = self.exec_binary(path);
+
+        // master keeps running, new workers must not inherit descriptors
+        for fd in fds {
+            set_cloexec(fd, true);
+        }
+        err
+    }
+
+    fn exec_binary(&self, path: &OsStr) -> io::Error {
+        // binary file could be replaced by new version
+        let exe = match std::env::current_exe() {
+            Ok(exe) => exe.into_os_string(),
+            Err(err) => return err,
+        };
+        let exe = exe.as_bytes();
+        let exe = if exe.ends_with(b" (deleted)") {
+            &exe[..exe.len() - b" (deleted)".len()]
+        } else {
+            exe
+        };
+
+        let args: Result<Vec<_>, _> = std::env::args_os()
+            .map(|arg| CString::new(arg.as_bytes()))
+            .collect();
+        let (exe, args) = match (CString::new(exe), args) {
+            (Ok(exe), Ok(args)) => (exe, args),
+            (Err(err), _) | (_, Err(err)) => return err.into(),
+        };
+
+        std::env::set_var(UPGRADE_VAR, path);
+        let res = execv(&exe, &args);
+        std::env::remove_var(UPGRADE_VAR);
+
+        match res {
+            Err(err) => io::Error::new(io::ErrorKind::Other, format!("{}", err)),
+            Ok(_) => unreachable!(),
+        }
+    }
+}
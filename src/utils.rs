@@ -4,6 +4,8 @@ use std::path::Path;
 
 use nix::unistd::Pid;
 
+use notify;
+
 /// find file in `PATH` environ
 pub(crate) fn find_path(name: &str) -> Option<String> {
     let path = Path::new(name);
@@ -27,7 +29,7 @@ pub(crate) fn find_path(name: &str) -> Option<String> {
 pub fn get_env_vars(all: bool) -> Vec<CString> {
     let mut env = Vec::new();
     for (k, v) in env::vars() {
-        if (all && !k.starts_with('_'))
+        if (all && !k.starts_with('_') && !notify::ENV_VARS.contains(&k.as_str()))
             || (k.starts_with("FECTL_FD")
                 || k.starts_with("LANG")
                 || k.starts_with("LC_"))
@@ -1,13 +1,23 @@
 use std;
+use std::collections::BTreeMap;
 use std::time::{Duration, Instant};
 
 use actix::prelude::*;
+use futures::{future, Future};
+use nix::sys::signal::Signal;
 use nix::unistd::Pid;
+use serde_json as json;
+use tokio::timer::Delay;
 
 use config::ServiceConfig;
-use event::{Events, Reason, State};
+use event::{Event, Events, Reason, State};
+use history::{self, History};
+use logging;
 use process::{self, Process, ProcessError};
-use service::FeService;
+use reaper;
+use restart::{self, Backoff, StartLimit};
+use service::{self, FeService};
+use upgrade::{ProcessHandover, WorkerHandover};
 use utils::str;
 
 #[allow(non_camel_case_types)]
@@ -21,6 +31,8 @@ pub enum WorkerCommand {
     stop,
     /// master heartbeat
     hb,
+    /// reopen log files
+    reopen,
 }
 
 #[allow(non_camel_case_types)]
@@ -39,6 +51,15 @@ pub enum WorkerMessage {
     cfgerror(String),
     /// heartbeat
     hb,
+    /// worker load, i.e. busy fraction or queue depth
+    load(f64),
+    /// log record, emitted through master logging
+    log {
+        level: String,
+        msg: String,
+        #[serde(default)]
+        fields: BTreeMap<String, json::Value>,
+    },
 }
 
 enum WorkerState {
@@ -46,9 +67,13 @@ enum WorkerState {
     Starting(ProcessInfo),
     Reloading(ProcessInfo, ProcessInfo),
     Restarting(ProcessInfo, ProcessInfo),
+    /// old process keeps running until new process gets started after restart
+    /// delay, flag is set for graceful reload
+    Respawning(ProcessInfo, bool),
     Running(ProcessInfo),
     StoppingOld(ProcessInfo, ProcessInfo),
     Stopping(ProcessInfo),
+    Waiting(Reason),
     Failed,
     Stopped,
 }
@@ -61,7 +86,12 @@ struct ProcessInfo {
 impl ProcessInfo {
     fn stop(&self) {
         if let Some(ref addr) = self.addr {
-            addr.do_send(process::StopProcess);
+            addr.do_send(process::StopProcess(false));
+        }
+    }
+    fn stop_old(&self) {
+        if let Some(ref addr) = self.addr {
+            addr.do_send(process::StopProcess(true));
         }
     }
     fn quit(&self, graceful: bool) {
@@ -84,6 +114,11 @@ impl ProcessInfo {
             addr.do_send(process::ResumeProcess);
         }
     }
+    fn reopen(&self) {
+        if let Some(ref addr) = self.addr {
+            addr.do_send(process::SendCommand(WorkerCommand::reopen));
+        }
+    }
 }
 
 pub struct Worker {
@@ -94,7 +129,20 @@ pub struct Worker {
     pub restore_from_fail: bool,
     started: Instant,
     restarts: u16,
+    backoff: Backoff,
+    start_limit: StartLimit,
+    start_stopped: Option<Reason>,
+    waiting: usize,
+    /// restarts are suspended during master upgrade
+    frozen: bool,
+    /// start of new process is postponed until worker is unfrozen
+    pending: bool,
+    /// worker failed and restart policy does not restart it
+    policy_exit: bool,
+    load: Option<(Pid, f64)>,
+    generation: u64,
     addr: Addr<FeService>,
+    history: Addr<History>,
 }
 
 impl Worker {
@@ -108,22 +156,195 @@ impl Worker {
             started: Instant::now(),
             restore_from_fail: false,
             restarts: 0,
+            backoff: Backoff::new(idx),
+            start_limit: StartLimit::new(),
+            start_stopped: None,
+            waiting: 0,
+            frozen: false,
+            pending: false,
+            policy_exit: false,
+            load: None,
+            generation: 0,
+            history: System::current().registry().get::<History>(),
         }
     }
 
+    /// Record worker event, event is logged with structured fields
+    pub fn event(&mut self, state: State, reason: Reason, pid: Option<String>) {
+        let fields = vec![
+            ("service".to_owned(), self.cfg.name.clone()),
+            ("worker_idx".to_owned(), (self.idx + 1).to_string()),
+            ("pid".to_owned(), pid.clone().unwrap_or_default()),
+            ("state".to_owned(), format!("{:?}", state)),
+            ("reason".to_owned(), format!("{:?}", reason)),
+        ];
+        logging::with_fields(fields, || {
+            info!(
+                "{} worker({}) {:?}: {:?}",
+                self.cfg.name,
+                self.idx + 1,
+                state,
+                reason
+            )
+        });
+        let event = Event::new(state, reason, pid);
+        self.history.do_send(history::Record(
+            self.cfg.name.clone(),
+            self.idx + 1,
+            event.clone(),
+        ));
+        self.events.push(event);
+    }
+
+    // start new worker process
+    fn spawn(&mut self) -> ProcessInfo {
+        self.generation += 1;
+        let (pid, addr) = Process::start(self.idx, &self.cfg, self.addr.clone());
+        ProcessInfo { pid, addr }
+    }
+
     pub fn start(&mut self, reason: Reason) {
         let id = self.idx;
         match self.state {
-            WorkerState::Initial | WorkerState::Stopped | WorkerState::Failed => {
+            WorkerState::Initial
+            | WorkerState::Stopped
+            | WorkerState::Failed
+            | WorkerState::Waiting(_) => {
+                if self.is_stopped() || self.is_failed() {
+                    self.backoff.reset();
+                    self.start_limit.reset();
+                }
+                self.policy_exit = false;
+                if self.frozen {
+                    self.pending = true;
+                    self.state = WorkerState::Waiting(reason);
+                    return;
+                }
                 debug!("Starting worker process id: {:?}", id);
-                let (pid, addr) = Process::start(self.idx, &self.cfg, self.addr.clone());
-                self.state = WorkerState::Starting(ProcessInfo { pid, addr });
-                self.events.add(State::Starting, reason, str(pid));
+                let process = self.spawn();
+                self.event(State::Starting, reason, str(process.pid));
+                self.state = WorkerState::Starting(process);
             }
+            // start worker after it gets stopped
+            WorkerState::Stopping(_) => self.start_stopped = Some(reason),
             _ => (),
         }
     }
 
+    /// Restart exited worker according to restart policy
+    fn restart_exited(&mut self, pid: Pid, err: &ProcessError, reason: Reason) {
+        let success = restart::is_success(err, &self.cfg.success_exit_codes);
+
+        if self.cfg.restart.restart(success) {
+            self.restart_limited(reason);
+        } else {
+            info!(
+                "Worker exited (pid:{}), restart policy: {:?}",
+                pid, self.cfg.restart
+            );
+            self.policy_exit = !success;
+            self.state = if success {
+                WorkerState::Stopped
+            } else {
+                WorkerState::Failed
+            };
+        }
+    }
+
+    /// Restart worker, unless start limit is reached
+    fn restart_limited(&mut self, reason: Reason) {
+        if self.start_limit.check(&self.cfg) {
+            self.restart(reason);
+        } else {
+            error!(
+                "Worker restarted more than {} times within {} secs",
+                self.cfg.start_limit_burst, self.cfg.start_limit_interval
+            );
+            self.event(State::Failed, Reason::StartLimitReached, None);
+            self.state = WorkerState::Failed;
+        }
+    }
+
+    /// Start worker after restart delay
+    fn restart(&mut self, reason: Reason) {
+        let delay = self.backoff.next_delay(&self.cfg);
+        if delay == Duration::new(0, 0) {
+            self.state = WorkerState::Initial;
+            self.start(reason);
+            return;
+        }
+
+        info!(
+            "Restarting worker {} in {}.{:03} secs",
+            self.idx,
+            delay.as_secs(),
+            delay.subsec_millis()
+        );
+        self.state = WorkerState::Waiting(reason);
+        self.wait(delay);
+    }
+
+    /// Start new process of reloading worker after restart delay
+    fn respawn(&mut self, old: ProcessInfo, graceful: bool) {
+        let delay = self.backoff.next_delay(&self.cfg);
+        if delay == Duration::new(0, 0) {
+            return self.respawned(old, graceful);
+        }
+
+        info!(
+            "Starting new process of worker {} in {}.{:03} secs",
+            self.idx,
+            delay.as_secs(),
+            delay.subsec_millis()
+        );
+        self.state = WorkerState::Respawning(old, graceful);
+        self.wait(delay);
+    }
+
+    // notify service once restart delay is elapsed
+    fn wait(&mut self, delay: Duration) {
+        self.waiting += 1;
+
+        let (idx, waiting, addr) = (self.idx, self.waiting, self.addr.clone());
+        Arbiter::spawn(Delay::new(Instant::now() + delay).then(move |_| {
+            addr.do_send(service::RestartWorker(idx, waiting));
+            Ok(())
+        }));
+    }
+
+    /// Restart delay is elapsed
+    pub fn restart_delayed(&mut self, waiting: usize) {
+        if waiting != self.waiting {
+            return;
+        }
+        if self.frozen {
+            self.pending = true;
+            return;
+        }
+        let state = std::mem::replace(&mut self.state, WorkerState::Initial);
+
+        match state {
+            WorkerState::Waiting(reason) => self.start(reason),
+            WorkerState::Respawning(old, graceful) => self.respawned(old, graceful),
+            state => self.state = state,
+        }
+    }
+
+    // start new process, old process is stopped once new process is loaded
+    fn respawned(&mut self, old: ProcessInfo, graceful: bool) {
+        if self.frozen {
+            self.pending = true;
+            self.state = WorkerState::Respawning(old, graceful);
+            return;
+        }
+        let info = self.spawn();
+        self.state = if graceful {
+            WorkerState::Reloading(info, old)
+        } else {
+            WorkerState::Restarting(info, old)
+        };
+    }
+
     pub fn loaded(&mut self, pid: Pid) {
         let state = std::mem::replace(&mut self.state, WorkerState::Initial);
 
@@ -131,8 +352,9 @@ impl Worker {
             WorkerState::Starting(p) => {
                 if p.pid == pid {
                     self.restarts = 0;
+                    self.backoff.loaded();
                     p.start();
-                    self.events.add(State::Running, Reason::None, str(p.pid));
+                    self.event(State::Running, Reason::None, str(p.pid));
                     self.state = WorkerState::Running(p);
                     self.restore_from_fail = false;
                 } else {
@@ -142,10 +364,10 @@ impl Worker {
             WorkerState::Reloading(p, old) => {
                 if p.pid == pid {
                     self.restarts = 0;
-                    old.stop();
+                    self.backoff.loaded();
+                    old.stop_old();
                     p.start();
-                    self.events
-                        .add(State::StoppingOld, Reason::None, str(old.pid));
+                    self.event(State::StoppingOld, Reason::None, str(old.pid));
                     self.state = WorkerState::StoppingOld(p, old);
                 } else {
                     self.state = WorkerState::Reloading(p, old);
@@ -154,10 +376,10 @@ impl Worker {
             WorkerState::Restarting(p, old) => {
                 if p.pid == pid {
                     self.restarts = 0;
+                    self.backoff.loaded();
                     old.quit(true);
                     p.start();
-                    self.events
-                        .add(State::StoppingOld, Reason::None, str(old.pid));
+                    self.event(State::StoppingOld, Reason::None, str(old.pid));
                     self.state = WorkerState::StoppingOld(p, old);
                 } else {
                     self.state = WorkerState::Restarting(p, old);
@@ -182,6 +404,12 @@ impl Worker {
         }
     }
 
+    /// Check if failed worker can be restarted by service auto-recovery,
+    /// worker left failed by restart policy stays failed
+    pub fn is_recoverable(&self) -> bool {
+        self.is_failed() && !self.is_running() && !self.policy_exit
+    }
+
     pub fn is_stopped(&self) -> bool {
         match self.state {
             WorkerState::Stopped => true,
@@ -189,35 +417,202 @@ impl Worker {
         }
     }
 
+    /// Number of processes started by worker
+    pub fn generation(&self) -> u64 {
+        self.generation
+    }
+
+    /// Continue generations of previous master process
+    pub fn set_generation(&mut self, generation: u64) {
+        self.generation = generation;
+    }
+
+    /// Update worker config, new config is used for new processes
+    pub fn set_config(&mut self, cfg: ServiceConfig) {
+        self.cfg = cfg;
+    }
+
+    /// Last load reported by running worker process
+    pub fn load(&self) -> Option<f64> {
+        match (&self.state, self.load) {
+            (&WorkerState::Running(ref process), Some((pid, load))) => {
+                if process.pid == pid {
+                    Some(load)
+                } else {
+                    None
+                }
+            }
+            _ => None,
+        }
+    }
+
     pub fn pid(&self) -> Option<Pid> {
         match self.state {
             WorkerState::Running(ref process) => Some(process.pid),
             WorkerState::StoppingOld(ref process, _) => Some(process.pid),
+            WorkerState::Respawning(ref process, _) => Some(process.pid),
             _ => None,
         }
     }
 
+    /// Pids of all worker processes, including old and starting processes
+    pub fn pids(&self) -> Vec<Pid> {
+        match self.state {
+            WorkerState::Starting(ref p)
+            | WorkerState::Running(ref p)
+            | WorkerState::Respawning(ref p, _)
+            | WorkerState::Stopping(ref p) => vec![p.pid],
+            WorkerState::Reloading(ref p, ref old)
+            | WorkerState::Restarting(ref p, ref old)
+            | WorkerState::StoppingOld(ref p, ref old) => vec![p.pid, old.pid],
+            _ => Vec::new(),
+        }
+    }
+
+    /// Suspend restarts of the worker, processes are not started until
+    /// worker gets unfrozen
+    pub fn freeze(&mut self) {
+        self.frozen = true;
+    }
+
+    /// Resume restarts, postponed process gets started immediately
+    pub fn unfreeze(&mut self) {
+        self.frozen = false;
+        if !self.pending {
+            return;
+        }
+        self.pending = false;
+
+        let state = std::mem::replace(&mut self.state, WorkerState::Initial);
+        match state {
+            WorkerState::Waiting(reason) => self.start(reason),
+            WorkerState::Respawning(old, graceful) => self.respawned(old, graceful),
+            state => self.state = state,
+        }
+    }
+
+    /// Worker state for upgraded master process
+    ///
+    /// Only loaded process is handed over, starting processes get terminated
+    /// by new master process and worker is started again.
+    pub fn handover(&self) -> Box<Future<Item = WorkerHandover, Error = ()>> {
+        let (process, restart, terminate) = match self.state {
+            WorkerState::Running(ref p) => (Some(p), false, Vec::new()),
+            WorkerState::Respawning(ref old, _) => (Some(old), true, Vec::new()),
+            WorkerState::Reloading(ref p, ref old)
+            | WorkerState::Restarting(ref p, ref old) => (Some(old), true, vec![p.pid]),
+            WorkerState::StoppingOld(ref p, ref old) => (Some(p), false, vec![old.pid]),
+            WorkerState::Starting(ref p) => (None, true, vec![p.pid]),
+            WorkerState::Stopping(ref p) => {
+                (None, self.start_stopped.is_some(), vec![p.pid])
+            }
+            WorkerState::Waiting(_) => (None, true, Vec::new()),
+            WorkerState::Initial | WorkerState::Failed | WorkerState::Stopped => {
+                (None, false, Vec::new())
+            }
+        };
+
+        let mut handover = WorkerHandover {
+            idx: self.idx,
+            process: None,
+            failed: self.is_failed(),
+            restart,
+            terminate: terminate.iter().map(|pid| pid.as_raw()).collect(),
+            events: Vec::from(&self.events),
+        };
+
+        match process.and_then(|p| p.addr.as_ref().map(|addr| (p.pid, addr))) {
+            Some((pid, addr)) => {
+                Box::new(addr.send(process::Handover).map_err(|_| ()).map(
+                    move |((read, write), output)| {
+                        handover.process = Some(ProcessHandover {
+                            pid: pid.as_raw(),
+                            read,
+                            write,
+                            output,
+                        });
+                        handover
+                    },
+                ))
+            }
+            None => {
+                // process without pipes can not be adopted
+                if let Some(p) = process {
+                    handover.restart = true;
+                    handover.terminate.push(p.pid.as_raw());
+                }
+                Box::new(future::ok(handover))
+            }
+        }
+    }
+
+    /// Restore worker handed over by previous master process
+    pub fn adopt(&mut self, worker: WorkerHandover) {
+        self.events.extend(worker.events);
+
+        // processes that were starting or stopping during upgrade
+        for pid in worker.terminate {
+            warn!("Killing process of worker {} (pid:{})", self.idx, pid);
+            reaper::signal(self.cfg.kill_mode, Pid::from_raw(pid), Signal::SIGKILL);
+        }
+
+        self.state = match worker.process {
+            Some(process) => {
+                let pid = Pid::from_raw(process.pid);
+                let addr = Process::adopt(
+                    self.idx,
+                    pid,
+                    (process.read, process.write),
+                    process.output,
+                    &self.cfg,
+                    self.addr.clone(),
+                );
+                self.restore_from_fail = worker.failed;
+                self.event(State::Running, Reason::MasterUpgrade, str(pid));
+                WorkerState::Running(ProcessInfo {
+                    pid,
+                    addr: Some(addr),
+                })
+            }
+            None => {
+                if worker.failed {
+                    WorkerState::Failed
+                } else {
+                    WorkerState::Stopped
+                }
+            }
+        };
+
+        // worker was restarting during upgrade
+        if worker.restart {
+            if self.is_running() {
+                self.reload(true, Reason::MasterUpgrade);
+            } else {
+                self.state = WorkerState::Initial;
+                self.start(Reason::MasterUpgrade);
+            }
+        }
+    }
+
     pub fn reload(&mut self, graceful: bool, reason: Reason) {
         let state = std::mem::replace(&mut self.state, WorkerState::Initial);
 
         match state {
             WorkerState::Running(process) => {
-                // start new worker
-                let (pid, addr) = Process::start(self.idx, &self.cfg, self.addr.clone());
-                let info = ProcessInfo { pid, addr };
-
                 if graceful {
                     info!("Reloading worker: (pid:{})", process.pid);
-                    self.events.add(State::Reloading, reason, str(process.pid));
-                    self.state = WorkerState::Reloading(info, process);
+                    self.event(State::Reloading, reason, str(process.pid));
                 } else {
                     info!("Restarting worker: (pid:{})", process.pid);
-                    self.events.add(State::Restarting, reason, str(process.pid));
-                    self.state = WorkerState::Restarting(info, process);
+                    self.event(State::Restarting, reason, str(process.pid));
                 }
+                // start new worker
+                self.respawned(process, graceful);
             }
-            WorkerState::Failed | WorkerState::Stopped => {
+            WorkerState::Failed | WorkerState::Stopped | WorkerState::Waiting(_) => {
                 self.restarts = 0;
+                self.backoff.reset();
+                self.start_limit.reset();
                 self.state = WorkerState::Initial;
                 self.start(reason);
             }
@@ -227,15 +622,19 @@ impl Worker {
 
     pub fn stop(&mut self, reason: Reason) {
         let state = std::mem::replace(&mut self.state, WorkerState::Initial);
+        self.start_stopped = None;
 
         match state {
-            WorkerState::Initial | WorkerState::Stopped | WorkerState::Failed => {
+            WorkerState::Initial
+            | WorkerState::Stopped
+            | WorkerState::Failed
+            | WorkerState::Waiting(_) => {
                 self.state = WorkerState::Stopped;
-                self.events.add(State::Stopped, reason, None);
+                self.event(State::Stopped, reason, None);
             }
             WorkerState::Starting(process) => {
                 process.quit(true);
-                self.events.add(State::Stopping, reason, str(process.pid));
+                self.event(State::Stopping, reason, str(process.pid));
                 self.state = WorkerState::Stopping(process);
             }
             WorkerState::Stopping(process) => {
@@ -244,24 +643,24 @@ impl Worker {
             WorkerState::StoppingOld(process, old_proc) => {
                 old_proc.quit(true);
                 process.stop();
-                self.events.add(State::Stopping, reason, str(process.pid));
+                self.event(State::Stopping, reason, str(process.pid));
                 self.state = WorkerState::Stopping(process);
             }
-            WorkerState::Running(process) => {
+            WorkerState::Running(process) | WorkerState::Respawning(process, _) => {
                 process.stop();
-                self.events.add(State::Stopping, reason, str(process.pid));
+                self.event(State::Stopping, reason, str(process.pid));
                 self.state = WorkerState::Stopping(process);
             }
             WorkerState::Reloading(process, old_proc) => {
                 process.quit(true);
                 old_proc.stop();
-                self.events.add(State::Stopping, reason, str(old_proc.pid));
+                self.event(State::Stopping, reason, str(old_proc.pid));
                 self.state = WorkerState::Stopping(old_proc);
             }
             WorkerState::Restarting(process, old_proc) => {
                 process.quit(true);
                 old_proc.stop();
-                self.events.add(State::Stopping, reason, str(old_proc.pid));
+                self.event(State::Stopping, reason, str(old_proc.pid));
                 self.state = WorkerState::Stopping(old_proc);
             }
         }
@@ -269,15 +668,19 @@ impl Worker {
 
     pub fn quit(&mut self, reason: Reason) {
         let state = std::mem::replace(&mut self.state, WorkerState::Initial);
+        self.start_stopped = None;
 
         match state {
-            WorkerState::Initial | WorkerState::Stopped | WorkerState::Failed => {
+            WorkerState::Initial
+            | WorkerState::Stopped
+            | WorkerState::Failed
+            | WorkerState::Waiting(_) => {
                 self.state = WorkerState::Stopped;
-                self.events.add(State::Stopped, reason, None);
+                self.event(State::Stopped, reason, None);
             }
             WorkerState::Starting(process) => {
                 process.quit(true);
-                self.events.add(State::Stopping, reason, str(process.pid));
+                self.event(State::Stopping, reason, str(process.pid));
                 self.state = WorkerState::Stopping(process);
             }
             WorkerState::Stopping(process) => {
@@ -286,25 +689,24 @@ impl Worker {
             WorkerState::StoppingOld(process, old_proc) => {
                 old_proc.quit(true);
                 process.quit(true);
-                self.events
-                    .add(State::StoppingOld, reason, str(process.pid));
+                self.event(State::StoppingOld, reason, str(process.pid));
                 self.state = WorkerState::Stopping(process);
             }
-            WorkerState::Running(process) => {
+            WorkerState::Running(process) | WorkerState::Respawning(process, _) => {
                 process.quit(true);
-                self.events.add(State::Stopping, reason, str(process.pid));
+                self.event(State::Stopping, reason, str(process.pid));
                 self.state = WorkerState::Stopping(process);
             }
             WorkerState::Reloading(process, old_proc) => {
                 process.quit(true);
                 old_proc.quit(true);
-                self.events.add(State::Stopping, reason, str(old_proc.pid));
+                self.event(State::Stopping, reason, str(old_proc.pid));
                 self.state = WorkerState::Stopping(old_proc);
             }
             WorkerState::Restarting(process, old_proc) => {
                 process.quit(true);
                 old_proc.quit(true);
-                self.events.add(State::Stopping, reason, str(old_proc.pid));
+                self.event(State::Stopping, reason, str(old_proc.pid));
                 self.state = WorkerState::Stopping(old_proc);
             }
         }
@@ -320,22 +722,46 @@ impl Worker {
             match *message {
                 WorkerMessage::reload => self.reload(true, Reason::WorkerRequest),
                 WorkerMessage::restart => self.reload(false, Reason::WorkerRequest),
+                WorkerMessage::load(load) => self.load = Some((pid, load)),
                 _ => (),
             }
         }
     }
 
+    /// Signal is sent to worker process during stop sequence
+    pub fn signaled(&mut self, pid: Pid, signal: Signal) {
+        let state = match self.state {
+            WorkerState::StoppingOld(_, ref old) if old.pid == pid => State::StoppingOld,
+            WorkerState::Stopping(ref process) if process.pid == pid => State::Stopping,
+            _ => return,
+        };
+        self.event(state, Reason::StopSignal(format!("{:?}", signal)), str(pid));
+    }
+
     pub fn pause(&mut self, reason: Reason) {
         if let WorkerState::Running(ref process) = self.state {
             process.pause();
-            self.events.add(State::Paused, reason, str(process.pid));
+            self.event(State::Paused, reason, str(process.pid));
+        }
+    }
+
+    /// Ask loaded worker processes to reopen log files, starting process
+    /// expects `prepare` command first
+    pub fn reopen_logs(&self) {
+        match self.state {
+            WorkerState::Running(ref p)
+            | WorkerState::Respawning(ref p, _)
+            | WorkerState::StoppingOld(ref p, _)
+            | WorkerState::Reloading(_, ref p)
+            | WorkerState::Restarting(_, ref p) => p.reopen(),
+            _ => (),
         }
     }
 
     pub fn resume(&mut self, reason: Reason) {
         if let WorkerState::Running(ref process) = self.state {
             process.resume();
-            self.events.add(State::Running, reason, str(process.pid));
+            self.event(State::Running, reason, str(process.pid));
         }
     }
 
@@ -350,7 +776,7 @@ impl Worker {
                     match *err {
                         ProcessError::StartupTimeout => {
                             self.state = WorkerState::Running(process);
-                            self.events.add(State::Running, err.into(), str(pid));
+                            self.event(State::Running, err.into(), str(pid));
                             self.restore_from_fail = true;
                             self.reload(false, Reason::ReloadAftreTimeout);
                             return;
@@ -362,8 +788,12 @@ impl Worker {
                             // start new worker
                             self.started = Instant::now();
                             self.state = WorkerState::Initial;
-                            self.events.add(State::Stopped, err.into(), str(pid));
-                            self.start(Reason::RestartFailedRunningWorker);
+                            self.event(State::Stopped, err.into(), str(pid));
+                            self.restart_exited(
+                                pid,
+                                err,
+                                Reason::RestartFailedRunningWorker,
+                            );
                         }
                     }
                 }
@@ -373,17 +803,40 @@ impl Worker {
                 if process.pid != pid {
                     self.state = WorkerState::Starting(process);
                 } else {
+                    let success = restart::is_success(err, &self.cfg.success_exit_codes);
+                    if !self.cfg.restart.restart(success) {
+                        // just in case
+                        process.quit(false);
+
+                        info!(
+                            "Worker exited (pid:{}), restart policy: {:?}",
+                            pid, self.cfg.restart
+                        );
+                        if success {
+                            self.event(State::Stopped, err.into(), str(pid));
+                            self.state = WorkerState::Stopped;
+                        } else {
+                            self.event(State::Failed, err.into(), str(pid));
+                            self.state = WorkerState::Failed;
+                        }
+                        return;
+                    }
+
                     match *err {
                         // can not boot worker, fail immediately
                         //&ProcessError::InitFailed | &ProcessError::BootFailed => {
                         //    self.state = WorkerState::Failed;
-                        //    self.events.add(State::Failed, Reason::from(err), str(pid));
+                        //    self.event(State::Failed, Reason::from(err), str(pid));
                         //    return
                         //}
                         ProcessError::ExitCode(0) => {
                             // check for fast restart
                             let now = Instant::now();
-                            if now.duration_since(self.started) > Duration::new(10, 0) {
+                            let stable = Duration::new(
+                                u64::from(self.cfg.restart_stable_period),
+                                0,
+                            );
+                            if now.duration_since(self.started) > stable {
                                 self.started = now;
                                 self.restarts = 0;
                             } else {
@@ -393,15 +846,14 @@ impl Worker {
                         _ => self.restarts += 1,
                     }
 
-                    self.events.add(State::Failed, Reason::from(err), str(pid));
+                    self.event(State::Failed, Reason::from(err), str(pid));
 
                     if self.restarts < self.cfg.restarts {
                         // just in case
                         process.quit(false);
 
                         // start new worker
-                        self.state = WorkerState::Initial;
-                        self.start(Reason::RestartFailedStartingWorker);
+                        self.restart_limited(Reason::RestartFailedStartingWorker);
                     } else {
                         error!("Can not start worker (pid:{})", process.pid);
                         self.state = WorkerState::Failed;
@@ -417,8 +869,8 @@ impl Worker {
                         //    error!("Can not start worker (pid:{}), restoring old worker",
                         //           process.pid);
                         //    self.restore_from_fail = true;
-                        //    self.events.add(State::ReloadFailed, err.into(), str(pid));
-                        //    self.events.add(State::Running,
+                        //    self.event(State::ReloadFailed, err.into(), str(pid));
+                        //    self.event(State::Running,
                         //                    Reason::RestoreAfterFailed, str(old_proc.pid));
                         //    self.state = WorkerState::Running(old_proc);
                         //    return
@@ -426,7 +878,11 @@ impl Worker {
                         ProcessError::ExitCode(0) => {
                             // check for fast restart
                             let now = Instant::now();
-                            if now.duration_since(self.started) > Duration::new(3, 0) {
+                            let stable = Duration::new(
+                                u64::from(self.cfg.restart_stable_period),
+                                0,
+                            );
+                            if now.duration_since(self.started) > stable {
                                 self.started = now;
                                 self.restarts = 0;
                             } else {
@@ -436,21 +892,18 @@ impl Worker {
                         _ => self.restarts += 1,
                     }
 
-                    self.events.add(State::ReloadFailed, err.into(), str(pid));
+                    self.event(State::ReloadFailed, err.into(), str(pid));
 
                     if self.restarts < self.cfg.restarts {
                         // start new worker
-                        let (pid, addr) =
-                            Process::start(self.idx, &self.cfg, self.addr.clone());
-                        let info = ProcessInfo { pid, addr };
-                        self.state = WorkerState::Reloading(info, old_proc);
+                        self.respawn(old_proc, true);
                     } else {
                         error!(
                             "Can not start worker (pid:{}), restoring old worker",
                             process.pid
                         );
                         self.restore_from_fail = true;
-                        self.events.add(
+                        self.event(
                             State::Running,
                             Reason::RestoreAftreFailed,
                             str(old_proc.pid),
@@ -459,9 +912,8 @@ impl Worker {
                     }
                 } else if old_proc.pid == pid {
                     self.restore_from_fail = false;
-                    self.events.add(State::Stopped, Reason::None, str(pid));
-                    self.events
-                        .add(State::Running, Reason::None, str(process.pid));
+                    self.event(State::Stopped, Reason::None, str(pid));
+                    self.event(State::Running, Reason::None, str(process.pid));
                     self.state = WorkerState::Running(process);
                 } else {
                     self.state = WorkerState::Reloading(process, old_proc);
@@ -476,8 +928,8 @@ impl Worker {
                         //    error!("Can not start worker (pid:{}), restoring old worker",
                         //           process.pid);
                         //    self.restore_from_fail = true;
-                        //    self.events.add(State::RestartFailed, err.into(), str(pid));
-                        //    self.events.add(State::Running,
+                        //    self.event(State::RestartFailed, err.into(), str(pid));
+                        //    self.event(State::Running,
                         //                    Reason::RestoreAfterFailed, str(old_proc.pid));
                         //    self.state = WorkerState::Running(old_proc);
                         //    return
@@ -485,7 +937,11 @@ impl Worker {
                         ProcessError::ExitCode(0) => {
                             // check for fast restart
                             let now = Instant::now();
-                            if now.duration_since(self.started) > Duration::new(3, 0) {
+                            let stable = Duration::new(
+                                u64::from(self.cfg.restart_stable_period),
+                                0,
+                            );
+                            if now.duration_since(self.started) > stable {
                                 self.started = now;
                                 self.restarts = 0;
                             } else {
@@ -497,21 +953,18 @@ impl Worker {
                         }
                     }
 
-                    self.events.add(State::RestartFailed, err.into(), str(pid));
+                    self.event(State::RestartFailed, err.into(), str(pid));
 
                     if self.restarts < self.cfg.restarts {
                         // start new worker
-                        let (pid, addr) =
-                            Process::start(self.idx, &self.cfg, self.addr.clone());
-                        let info = ProcessInfo { pid, addr };
-                        self.state = WorkerState::Restarting(info, old_proc);
+                        self.respawn(old_proc, false);
                     } else {
                         error!(
                             "Can not start worker (pid:{}), restoring old worker",
                             process.pid
                         );
                         self.restore_from_fail = true;
-                        self.events.add(
+                        self.event(
                             State::Running,
                             Reason::RestoreAftreFailed,
                             str(old_proc.pid),
@@ -520,27 +973,35 @@ impl Worker {
                     }
                 } else if old_proc.pid == pid {
                     self.restore_from_fail = false;
-                    self.events.add(State::Stopped, Reason::None, str(pid));
-                    self.events
-                        .add(State::Running, Reason::None, str(process.pid));
+                    self.event(State::Stopped, Reason::None, str(pid));
+                    self.event(State::Running, Reason::None, str(process.pid));
                     self.state = WorkerState::Running(process);
                 } else {
                     self.state = WorkerState::Restarting(process, old_proc);
                 }
             }
+            WorkerState::Respawning(old_proc, graceful) => {
+                // old process died, start new process after restart delay
+                if old_proc.pid == pid {
+                    self.restore_from_fail = false;
+                    self.event(State::Stopped, err.into(), str(pid));
+                    self.state = WorkerState::Waiting(Reason::NewProcessDied);
+                } else {
+                    self.state = WorkerState::Respawning(old_proc, graceful);
+                }
+            }
             WorkerState::StoppingOld(process, old_proc) => {
                 // new process died, need to restart
                 if process.pid == pid {
                     old_proc.quit(false);
                     self.restarts += 1;
                     self.state = WorkerState::Initial;
-                    self.events.add(State::Failed, err.into(), str(pid));
-                    self.start(Reason::NewProcessDied);
+                    self.event(State::Failed, err.into(), str(pid));
+                    self.restart_exited(pid, err, Reason::NewProcessDied);
                 } else if old_proc.pid == pid {
                     self.restore_from_fail = false;
-                    self.events.add(State::Stopped, Reason::None, str(pid));
-                    self.events
-                        .add(State::Running, Reason::None, str(process.pid));
+                    self.event(State::Stopped, Reason::None, str(pid));
+                    self.event(State::Running, Reason::None, str(process.pid));
                     self.state = WorkerState::Running(process);
                 } else {
                     self.state = WorkerState::StoppingOld(process, old_proc);
@@ -549,7 +1010,10 @@ impl Worker {
             WorkerState::Stopping(process) => {
                 if process.pid == pid {
                     self.state = WorkerState::Stopped;
-                    self.events.add(State::Stopped, err.into(), str(pid));
+                    self.event(State::Stopped, err.into(), str(pid));
+                    if let Some(reason) = self.start_stopped.take() {
+                        self.start(reason);
+                    }
                 } else {
                     self.state = WorkerState::Stopping(process);
                 }
@@ -558,3 +1022,45 @@ impl Worker {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix::dev::channel;
+    use toml;
+
+    fn worker(restart: &str) -> Worker {
+        let cfg = toml::from_str(&format!(
+            "name = \"test\"\nnum = 1\ncommand = \"true\"\nrestart = \"{}\"",
+            restart
+        )).unwrap();
+        let (tx, _) = channel::channel::<FeService>(16);
+        Worker::new(0, cfg, Addr::new(tx))
+    }
+
+    #[test]
+    fn test_recovery_respects_restart_policy() {
+        System::run(|| {
+            let pid = Pid::from_raw(1);
+            let reason = || Reason::RestartFailedRunningWorker;
+
+            // failed by restart policy
+            let mut w = worker("never");
+            w.restart_exited(pid, &ProcessError::ExitCode(1), reason());
+            assert!(w.is_failed());
+            assert!(!w.is_recoverable());
+
+            let mut w = worker("on-failure");
+            w.restart_exited(pid, &ProcessError::ExitCode(0), reason());
+            assert!(w.is_stopped());
+            assert!(!w.is_recoverable());
+
+            // failed for other reason, i.e. start limit
+            let mut w = worker("always");
+            w.state = WorkerState::Failed;
+            assert!(w.is_recoverable());
+
+            System::current().stop();
+        });
+    }
+}